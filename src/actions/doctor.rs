@@ -0,0 +1,182 @@
+use std::io::{self, Write};
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+
+use crate::{
+    actions::prepare_commit_msg::{embeddings_base_url, try_get_llm_client},
+    error::SummarizeError,
+    llms::offline::OfflineClient,
+    settings::Settings,
+    summarize::SummarizationClient,
+};
+
+/// The prompt sent to check that the model answers.
+static PING_PROMPT: &str = "ping";
+
+pub(crate) async fn main(settings: Settings) -> Result<()> {
+    run(settings, &mut io::stdout()).await
+}
+
+/// Checks that the settings and prompt templates are valid and that the
+/// configured model answers a tiny prompt, as does the embeddings endpoint of
+/// `model.embeddings_base_url` when one is set, reporting each check to `out`.
+/// Fails when any check does.
+async fn run(settings: Settings, out: &mut impl Write) -> Result<()> {
+    let provider = settings.model_provider.clone().unwrap_or_default();
+    let model = settings.model_name();
+    writeln!(out, "Model provider: {provider}")?;
+    writeln!(out, "Model: {model}")?;
+
+    let mut failed = false;
+    // the prompts are checked without sending them
+    match SummarizationClient::new(settings.clone(), Box::new(OfflineClient)) {
+        Ok(_) => writeln!(out, "✓ The settings and prompt templates are valid")?,
+        Err(e) => {
+            failed = true;
+            writeln!(out, "✗ The settings or prompt templates are invalid: {e}")?;
+        }
+    }
+
+    match try_get_llm_client(&settings) {
+        Ok(client) => {
+            let start = Instant::now();
+            match client.completions(PING_PROMPT).await {
+                Ok(_) => writeln!(
+                    out,
+                    "✓ {model} answered in {} ms",
+                    start.elapsed().as_millis()
+                )?,
+                Err(e) => {
+                    failed = true;
+                    writeln!(out, "✗ The request to {provider} failed: {e}")?;
+                    writeln!(out, "  {}", diagnose(&e))?;
+                }
+            }
+            if let Some(base_url) = embeddings_base_url(&settings) {
+                match client.embeddings(PING_PROMPT).await {
+                    Ok(_) => writeln!(out, "✓ The embeddings endpoint {base_url} answered")?,
+                    Err(e) => {
+                        failed = true;
+                        writeln!(out, "✗ The embeddings request to {base_url} failed: {e}")?;
+                        writeln!(out, "  {}", diagnose(&e))?;
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            failed = true;
+            writeln!(out, "✗ The {provider} client could not be created: {e}")?;
+        }
+    }
+
+    if failed {
+        bail!("gptcommit is not ready to summarize commits");
+    }
+    writeln!(out, "gptcommit is ready to summarize commits")?;
+    Ok(())
+}
+
+/// What to check for a failed request.
+fn diagnose(error: &SummarizeError) -> &'static str {
+    match error {
+        SummarizeError::Auth(_) => "The API key was rejected. Check `openai.api_key` or `openrouter.api_key`, or the `OPENAI_API_KEY` and `OPENROUTER_API_KEY` environment variables.",
+        SummarizeError::MissingApiKey(_) => "No API key is configured. Set `openai.api_key` or `openrouter.api_key`, or the `OPENAI_API_KEY` and `OPENROUTER_API_KEY` environment variables.",
+        SummarizeError::Network(_) => "The provider could not be reached. Check the `api_base` of the provider, `openai.proxy` and your network connection.",
+        SummarizeError::RateLimit(_) => "The rate limit or quota of the account was reached. Try again later, or check the plan and billing of the account.",
+        SummarizeError::BudgetExceeded(_) | SummarizeError::ContextLengthExceeded(_) => "The prompt was refused for its size. Check `limits.max_total_tokens` and the model.",
+        SummarizeError::Template(_) | SummarizeError::Config(_) => "Check the settings with `gptcommit config list`.",
+        SummarizeError::Cancelled => "The check was cancelled before the provider answered.",
+        SummarizeError::Provider(_) | SummarizeError::Other(_) => "The provider answered with an error. Check that the model exists and the account may use it.",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llms::test_server::serve_once;
+    use crate::settings::ModelProvider;
+
+    #[tokio::test]
+    async fn test_doctor_reports_a_working_setup() {
+        let mut settings = Settings::new().unwrap();
+        settings.model_provider = Some(ModelProvider::Mock);
+        let mut out = vec![];
+
+        run(settings, &mut out).await.unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Model provider: mock\n"));
+        assert!(out.contains("✓ The settings and prompt templates are valid\n"));
+        assert!(out.contains("✓ mock answered in "));
+        assert!(out.ends_with("gptcommit is ready to summarize commits\n"));
+    }
+
+    #[tokio::test]
+    async fn test_doctor_diagnoses_a_rejected_key() {
+        let (api_base, request) = serve_once(
+            401,
+            &[],
+            r#"{"error":{"message":"Incorrect API key provided","type":"invalid_request_error","code":"invalid_api_key"}}"#,
+        )
+        .await;
+        let mut settings = Settings::new().unwrap();
+        settings.model_provider = Some(ModelProvider::OpenAI);
+        let openai = settings.openai.as_mut().unwrap();
+        openai.api_base = Some(api_base);
+        openai.api_key = Some("sk-wrong".to_string());
+        openai.retries = Some(0);
+        settings.prompt.as_mut().unwrap().commit_title = Some("{{ summary_point }}".to_string());
+        let mut out = vec![];
+
+        let err = run(settings, &mut out).await.unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "gptcommit is not ready to summarize commits"
+        );
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("✗ The settings or prompt templates are invalid: "));
+        assert!(out.contains("summary_point"));
+        assert!(out.contains(
+            "✗ The request to openai failed: Authentication failed: Incorrect API key provided\n"
+        ));
+        assert!(out.contains("  The API key was rejected."));
+        assert!(request.await.unwrap().body.contains("ping"));
+    }
+
+    #[tokio::test]
+    async fn test_doctor_checks_the_embeddings_endpoint() {
+        let (api_base, _) = serve_once(
+            200,
+            &[],
+            r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-3.5-turbo","choices":[{"index":0,"message":{"role":"assistant","content":"pong"},"finish_reason":"stop"}]}"#,
+        )
+        .await;
+        let (embeddings_base_url, embeddings_request) = serve_once(
+            200,
+            &[],
+            r#"{"object":"list","model":"text-embedding-ada-002","data":[{"index":0,"object":"embedding","embedding":[0.5,-0.5]}],"usage":{"prompt_tokens":1,"total_tokens":1}}"#,
+        )
+        .await;
+        let mut settings = Settings::new().unwrap();
+        settings.model_provider = Some(ModelProvider::OpenAI);
+        let openai = settings.openai.as_mut().unwrap();
+        openai.api_base = Some(api_base);
+        openai.api_key = Some("sk-test".to_string());
+        openai.retries = Some(0);
+        settings.model.as_mut().unwrap().embeddings_base_url = Some(embeddings_base_url.clone());
+        let mut out = vec![];
+
+        run(settings, &mut out).await.unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!(
+            "✓ The embeddings endpoint {embeddings_base_url} answered\n"
+        )));
+        assert_eq!(
+            embeddings_request.await.unwrap().request_line,
+            "POST /v1/embeddings HTTP/1.1"
+        );
+    }
+}
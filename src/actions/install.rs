@@ -1,4 +1,7 @@
-use std::fs::{self};
+use std::{
+    fs::{self},
+    path::{Path, PathBuf},
+};
 
 #[cfg(unix)]
 use std::{fs::Permissions, os::unix::prelude::PermissionsExt};
@@ -13,6 +16,24 @@ use crate::{
     settings::{ModelProvider, OpenAISettings, Settings},
 };
 
+/// The hook script written on a fresh install.
+static HOOK_SCRIPT: &str = include_str!("../../prepare-commit-msg");
+/// The post-commit hook script written with `output.store_per_file_in_git_notes`.
+static POST_COMMIT_HOOK_SCRIPT: &str = include_str!("../../post-commit");
+/// Markers around the gptcommit block of a hook.
+pub(crate) static HOOK_BEGIN: &str = "### BEGIN GPTCOMMIT HOOK ###";
+pub(crate) static HOOK_END: &str = "### END GPTCOMMIT HOOK ###";
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum InstallOutcome {
+    Installed,
+    AlreadyInstalled,
+    /// An existing hook was backed up and the gptcommit block appended to it.
+    Appended {
+        backup_path: PathBuf,
+    },
+}
+
 pub(crate) async fn main(settings: Settings) -> Result<()> {
     println!("{}", "Installing gptcommit hook...".green().bold());
 
@@ -26,23 +47,47 @@ pub(crate) async fn main(settings: Settings) -> Result<()> {
         "Found git hooks path for current git repo {}",
         hooks_path.display()
     );
-    let prepare_commit_msg_path = hooks_path.join("prepare-commit-msg");
     println!(
         "Installing git hook to {}",
         hooks_path.display().to_string().bold()
     );
-    info!("Overwriting file at {}", prepare_commit_msg_path.display());
-    fs::write(
-        &prepare_commit_msg_path,
-        include_str!("../../prepare-commit-msg"),
-    )?;
-    #[cfg(unix)]
-    fs::set_permissions(&prepare_commit_msg_path, Permissions::from_mode(0o755))?;
+    match install_hook(&hooks_path)? {
+        InstallOutcome::Installed => println!(
+            "{}",
+            "gptcommit hook successfully installed!".green().bold(),
+        ),
+        InstallOutcome::AlreadyInstalled => {
+            println!("{}", "gptcommit hook is already installed.".green().bold())
+        }
+        InstallOutcome::Appended { backup_path } => {
+            println!(
+                "{}",
+                "gptcommit hook successfully added to the existing prepare-commit-msg hook!"
+                    .green()
+                    .bold(),
+            );
+            println!(
+                "The previous hook was backed up to {}",
+                backup_path.display().to_string().bold()
+            );
+        }
+    }
 
-    println!(
-        "{}",
-        "gptcommit hook successfully installed!".green().bold(),
-    );
+    let store_per_file_in_git_notes = settings
+        .output
+        .as_ref()
+        .and_then(|output| output.store_per_file_in_git_notes)
+        .unwrap_or(false);
+    if store_per_file_in_git_notes
+        && install_post_commit_hook(&hooks_path)? != InstallOutcome::AlreadyInstalled
+    {
+        println!(
+            "{}",
+            "gptcommit post-commit hook installed to store the per-file summaries in git notes!"
+                .green()
+                .bold(),
+        );
+    }
 
     if let Settings {
         model_provider: Some(ModelProvider::OpenAI),
@@ -57,3 +102,157 @@ pub(crate) async fn main(settings: Settings) -> Result<()> {
 
     Ok(())
 }
+
+/// Installs the prepare-commit-msg hook into `hooks_path`. An existing hook is
+/// kept: it is backed up and the gptcommit block is appended to it, unless the
+/// block is already there.
+pub(crate) fn install_hook(hooks_path: &Path) -> Result<InstallOutcome> {
+    install_hook_script(hooks_path, "prepare-commit-msg", HOOK_SCRIPT)
+}
+
+/// Installs the post-commit hook attaching the per-file summaries as a git
+/// note into `hooks_path`, the way [`install_hook`] does.
+pub(crate) fn install_post_commit_hook(hooks_path: &Path) -> Result<InstallOutcome> {
+    install_hook_script(hooks_path, "post-commit", POST_COMMIT_HOOK_SCRIPT)
+}
+
+fn install_hook_script(hooks_path: &Path, name: &str, script: &str) -> Result<InstallOutcome> {
+    let hook_path = hooks_path.join(name);
+    let outcome = if !hook_path.exists() {
+        info!("Writing file at {}", hook_path.display());
+        fs::write(&hook_path, script)?;
+        InstallOutcome::Installed
+    } else {
+        let existing = fs::read_to_string(&hook_path)?;
+        if existing.contains(HOOK_BEGIN) {
+            info!("{} already runs gptcommit", hook_path.display());
+            return Ok(InstallOutcome::AlreadyInstalled);
+        }
+        let backup_path = hooks_path.join(format!("{name}.backup"));
+        info!(
+            "Backing up {} to {}",
+            hook_path.display(),
+            backup_path.display()
+        );
+        fs::copy(&hook_path, &backup_path)?;
+        let mut contents = existing;
+        if !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents.push('\n');
+        contents.push_str(hook_block(script));
+        contents.push('\n');
+        fs::write(&hook_path, contents)?;
+        InstallOutcome::Appended { backup_path }
+    };
+    #[cfg(unix)]
+    fs::set_permissions(&hook_path, Permissions::from_mode(0o755))?;
+    Ok(outcome)
+}
+
+/// The lines of the hook script from [`HOOK_BEGIN`] through [`HOOK_END`].
+fn hook_block(script: &str) -> &str {
+    let start = script
+        .find(HOOK_BEGIN)
+        .expect("hook script has a begin marker");
+    let end = script
+        .find(HOOK_END)
+        .expect("hook script has an end marker")
+        + HOOK_END.len();
+    &script[start..end]
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use crate::git::get_hooks_path_in;
+
+    /// Creates an empty git repository in a fresh temp directory.
+    pub(crate) fn temp_repo(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("gptcommit-hook-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let status = Command::new("git")
+            .args(["init", "--quiet"])
+            .current_dir(&dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        dir
+    }
+
+    #[test]
+    fn test_fresh_install() {
+        let repo = temp_repo("fresh");
+        let hooks_path = get_hooks_path_in(&repo).unwrap();
+
+        assert_eq!(
+            install_hook(&hooks_path).unwrap(),
+            InstallOutcome::Installed
+        );
+        assert_eq!(
+            install_hook(&hooks_path).unwrap(),
+            InstallOutcome::AlreadyInstalled
+        );
+
+        let hook = fs::read_to_string(hooks_path.join("prepare-commit-msg")).unwrap();
+        assert_eq!(hook, HOOK_SCRIPT);
+        #[cfg(unix)]
+        assert_eq!(
+            fs::metadata(hooks_path.join("prepare-commit-msg"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o755
+        );
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_install_appends_to_existing_hook() {
+        let repo = temp_repo("existing");
+        let hooks_path = get_hooks_path_in(&repo).unwrap();
+        let existing = "#!/bin/sh\necho 'Signed-off-by: me' >> \"$1\"";
+        fs::write(hooks_path.join("prepare-commit-msg"), existing).unwrap();
+
+        let outcome = install_hook(&hooks_path).unwrap();
+        let backup_path = hooks_path.join("prepare-commit-msg.backup");
+        assert_eq!(
+            outcome,
+            InstallOutcome::Appended {
+                backup_path: backup_path.clone()
+            }
+        );
+        assert_eq!(fs::read_to_string(backup_path).unwrap(), existing);
+
+        let hook = fs::read_to_string(hooks_path.join("prepare-commit-msg")).unwrap();
+        assert_eq!(hook, format!("{existing}\n\n{}\n", hook_block(HOOK_SCRIPT)));
+        assert_eq!(
+            install_hook(&hooks_path).unwrap(),
+            InstallOutcome::AlreadyInstalled
+        );
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_install_honors_core_hooks_path() {
+        let repo = temp_repo("hooks-path");
+        let status = Command::new("git")
+            .args(["config", "core.hooksPath", ".githooks"])
+            .current_dir(&repo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let hooks_path = get_hooks_path_in(&repo).unwrap();
+        install_hook(&hooks_path).unwrap();
+
+        assert!(repo.join(".githooks/prepare-commit-msg").exists());
+        assert!(!repo.join(".git/hooks/prepare-commit-msg").exists());
+        fs::remove_dir_all(repo).unwrap();
+    }
+}
@@ -1,4 +1,6 @@
 pub(crate) mod config;
+pub(crate) mod doctor;
 pub(crate) mod install;
+pub(crate) mod post_commit;
 pub(crate) mod prepare_commit_msg;
 pub(crate) mod uninstall;
@@ -0,0 +1,28 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+
+use crate::{git, settings::Settings};
+
+/// Where prepare-commit-msg leaves the note for the commit being made, inside the git directory.
+pub(crate) static PENDING_NOTE_FILE: &str = "gptcommit/pending_note.txt";
+/// The notes ref the per-file summaries are stored under, `refs/notes/gptcommit`.
+pub(crate) static NOTES_REF: &str = "gptcommit";
+
+pub(crate) async fn main(_settings: Settings) -> Result<()> {
+    add_pending_note(Path::new("."), &git::get_git_path(PENDING_NOTE_FILE)?)
+}
+
+/// Attaches the note left by prepare-commit-msg to the new HEAD of `repo`
+/// and removes it. Does nothing when no note is pending, e.g. when the
+/// message wasn't generated.
+pub(crate) fn add_pending_note(repo: &Path, pending_note_file: &Path) -> Result<()> {
+    if !pending_note_file.exists() {
+        debug!("No pending note at {}", pending_note_file.display());
+        return Ok(());
+    }
+    git::add_note_in(repo, NOTES_REF, pending_note_file)?;
+    info!("Stored the per-file summaries in refs/notes/{NOTES_REF}");
+    fs::remove_file(pending_note_file)?;
+    Ok(())
+}
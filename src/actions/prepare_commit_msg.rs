@@ -1,5 +1,4 @@
-use anyhow::Result;
-use clap::arg;
+use anyhow::{anyhow, bail, Result};
 use clap::ValueEnum;
 use colored::Colorize;
 
@@ -7,18 +6,29 @@ use clap::Args;
 use strum_macros::Display;
 
 use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::str::FromStr;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::git;
+use tokio_util::sync::CancellationToken;
+
+use crate::{actions::post_commit::PENDING_NOTE_FILE, cache, git};
 
 use crate::help::print_help_openai_api_key;
-use crate::llms::{llm_client::LlmClient, openai::OpenAIClient};
-use crate::settings::ModelProvider;
+use crate::llms::{
+    llm_client::LlmClient, mock::MockClient, offline::OfflineClient, openai::OpenAIClient,
+    openrouter::OpenRouterClient,
+};
+use crate::settings::{HookErrorAction, Language, ModelProvider, OutputEncoding};
 
+use crate::error::is_missing_api_key;
 use crate::settings::Settings;
-use crate::summarize::SummarizationClient;
-use crate::util::SplitPrefixInclusive;
+use crate::summarize::{
+    is_work_in_progress, CommitMessageParts, MessagePart, ProgressWriter, SummarizationClient,
+};
+use crate::util::{self, SplitPrefixInclusive};
+use crate::SummarizeError;
 
 use crate::llms::tester_foobar::FooBarClient;
 
@@ -50,56 +60,452 @@ pub(crate) struct PrepareCommitMsgArgs {
     #[arg(long)]
     commit_sha: Option<String>,
 
-    /// Debugging tool to mock git repo state
+    /// Summarize the unified diff in this file instead of the staged changes
+    #[arg(long, alias = "git-diff-content", conflicts_with = "diff_from_stdin")]
+    diff_file: Option<PathBuf>,
+
+    /// Summarize the unified diff read from stdin instead of the staged changes
     #[arg(long)]
-    git_diff_content: Option<PathBuf>,
+    diff_from_stdin: bool,
+
+    /// Summarize the staged and unstaged changes to tracked files, as `git diff HEAD`
+    /// shows them, instead of only the staged changes
+    #[arg(long, alias = "working-tree")]
+    include_unstaged: bool,
+
+    /// Summarize the combined changes of a revision range, e.g. `main..feature`,
+    /// into one message, with the subjects of its commits as context
+    #[arg(
+        long,
+        value_name = "REV1..REV2",
+        value_parser = parse_range,
+        conflicts_with_all = ["diff_file", "diff_from_stdin", "include_unstaged"]
+    )]
+    range: Option<String>,
+
+    /// Only summarize the changes to paths matching these git pathspecs, e.g. `packages/web`
+    #[arg(value_name = "PATHSPEC")]
+    pathspecs: Vec<String>,
+
+    /// Only summarize the files matching this glob, e.g. `src/**`. Can be repeated
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    only: Vec<String>,
+
+    /// Don't summarize the files matching this glob, even if selected by `--only`. Can be repeated
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    exclude: Vec<String>,
+
+    /// Add a `Co-authored-by` trailer, as "Name <email>". Can be repeated
+    #[arg(long = "co-author", value_name = "CO_AUTHOR")]
+    co_authors: Vec<String>,
+
+    /// Write a minimal `wip: <area>` message without summarizing the changes
+    #[arg(long)]
+    wip: bool,
+
+    /// Generate only the title or the body of the last generated message again,
+    /// keeping its other parts, without summarizing the changes again
+    #[arg(long, value_enum, value_name = "PART", conflicts_with = "wip")]
+    regenerate: Option<MessagePart>,
+
+    /// Request temperature 0 and a fixed seed from the model for reproducible messages
+    #[arg(long)]
+    deterministic: bool,
+
+    /// Output language of the commit message for this run, overriding `output.lang`
+    #[arg(long, value_parser = parse_language)]
+    lang: Option<String>,
+
+    /// Use this conventional commit type, e.g. `fix`, instead of inferring it
+    #[arg(long = "type", value_name = "TYPE", value_parser = parse_commit_type)]
+    commit_type: Option<String>,
+
+    /// Whether to print each prompt and completion, set by the global `--transcript`
+    #[arg(skip)]
+    pub(crate) transcript: bool,
+
+    /// Where the parts of the message are kept for `--regenerate`, inside the git directory
+    #[arg(skip)]
+    last_message_file: Option<PathBuf>,
+
+    /// Where the git note of the per-file summaries is left for the post-commit hook
+    #[arg(skip)]
+    pending_note_file: Option<PathBuf>,
+
+    /// Cancelled on Ctrl-C, aborting the requests in flight
+    #[arg(skip)]
+    cancellation: CancellationToken,
+}
+
+fn parse_language(langs: &str) -> Result<String, String> {
+    match langs
+        .split(',')
+        .map(str::trim)
+        .find(|lang| *lang != "auto" && Language::from_str(lang).is_err())
+    {
+        Some(lang) => Err(format!("Invalid language: {lang}.")),
+        None => Ok(langs.to_string()),
+    }
+}
+
+fn parse_range(range: &str) -> Result<String, String> {
+    if !range.contains("..") {
+        return Err(format!(
+            "Invalid range: {range}. Expected two revisions like `main..feature`"
+        ));
+    }
+    Ok(range.to_string())
+}
+
+fn parse_glob(pattern: &str) -> Result<String, String> {
+    glob::Pattern::new(pattern)
+        .map(|_| pattern.to_string())
+        .map_err(|e| format!("Invalid glob: {pattern}: {e}."))
+}
+
+/// Lowercases the type of `--type`, which is checked against
+/// `output.allowed_commit_types` once the settings are read.
+fn parse_commit_type(commit_type: &str) -> Result<String, String> {
+    Ok(commit_type.trim().to_ascii_lowercase())
+}
+
+/// Applies the per-invocation CLI options on top of the configured settings.
+fn apply_args_to_settings(mut settings: Settings, args: &PrepareCommitMsgArgs) -> Settings {
+    if !args.co_authors.is_empty() {
+        let git_settings = settings.git.get_or_insert_with(Default::default);
+        git_settings
+            .co_authors
+            .get_or_insert_with(Vec::new)
+            .extend(args.co_authors.iter().cloned());
+    }
+    if args.deterministic {
+        settings
+            .output
+            .get_or_insert_with(Default::default)
+            .deterministic = Some(true);
+    }
+    if let Some(lang) = &args.lang {
+        settings.output.get_or_insert_with(Default::default).lang = Some(lang.to_string());
+    }
+    settings
+}
+
+/// Reads the diff to summarize from the source selected by the CLI arguments,
+/// defaulting to the staged changes of the current repository. A diff given
+/// with `--diff-file` or `--diff-from-stdin` takes precedence over `--include-unstaged`,
+/// which `--range` can't be combined with.
+fn read_diff(args: &PrepareCommitMsgArgs, stdin: &mut dyn Read) -> Result<String> {
+    if args.diff_file.is_some() || args.diff_from_stdin {
+        if args.include_unstaged {
+            warn!("--include-unstaged is ignored as the diff is given explicitly");
+        }
+        if !args.pathspecs.is_empty() {
+            warn!("the pathspecs are ignored as the diff is given explicitly");
+        }
+    }
+    if let Some(diff_file) = &args.diff_file {
+        Ok(fs::read_to_string(diff_file)?)
+    } else if args.diff_from_stdin {
+        let mut diff = String::new();
+        stdin.read_to_string(&mut diff)?;
+        Ok(diff)
+    } else if let Some(range) = &args.range {
+        git::get_range_diffs(range, &args.pathspecs)
+    } else {
+        let diff = if args.include_unstaged {
+            git::get_working_tree_diffs(&args.pathspecs)?
+        } else {
+            git::get_diffs(&args.pathspecs)?
+        };
+        amend_fallback(args, diff, |commit| {
+            git::get_commit_diff(commit, &args.pathspecs)
+        })
+    }
+}
+
+/// The context of a message squashing a range: the subjects of its commits,
+/// followed by the message already written, if any.
+fn range_context(original_message: &str, subjects: &[String]) -> String {
+    let mut context = subjects
+        .iter()
+        .map(|subject| format!("- {subject}\n"))
+        .collect::<String>();
+    if !context.is_empty() {
+        context.insert_str(0, "Commits being squashed:\n");
+    }
+    if !util::strip_git_comments(original_message).trim().is_empty() {
+        context.push('\n');
+        context.push_str(original_message);
+    }
+    context
+}
+
+/// Keeps the file diffs selected by `--only` and `--exclude`: a file has to match
+/// one of the `--only` globs, if any are given, and none of the `--exclude` globs,
+/// which take precedence. The `file_ignore` setting still applies to the rest.
+fn select_file_diffs<'a>(
+    file_diffs: Vec<&'a str>,
+    only: &[String],
+    exclude: &[String],
+) -> Vec<&'a str> {
+    file_diffs
+        .into_iter()
+        .filter(|file_diff| match util::get_file_name_from_diff(file_diff) {
+            Some(file_name) => {
+                (only.is_empty() || util::matches_any_glob(file_name, only))
+                    && !util::matches_any_glob(file_name, exclude)
+            }
+            None => only.is_empty(),
+        })
+        .collect()
+}
+
+/// When amending without staged changes only the message is rewritten, so the
+/// diff of the commit being amended is summarized instead of the empty staged diff.
+fn amend_fallback(
+    args: &PrepareCommitMsgArgs,
+    staged_diff: String,
+    get_commit_diff: impl FnOnce(&str) -> Result<String>,
+) -> Result<String> {
+    if args.commit_source != CommitSource::Commit || !staged_diff.trim().is_empty() {
+        return Ok(staged_diff);
+    }
+    let commit = args.commit_sha.as_deref().unwrap_or("HEAD");
+    debug!("nothing staged while amending, summarizing the diff of {commit}");
+    get_commit_diff(commit)
+}
+
+/// Where the parts of the last generated message are kept for `--regenerate`,
+/// inside the git directory.
+const LAST_MESSAGE_FILE: &str = "gptcommit/last_message.json";
+
+fn load_message_parts(path: Option<&Path>) -> Result<CommitMessageParts> {
+    match path.map(cache::read_locked).transpose()?.flatten() {
+        Some(contents) => Ok(serde_json::from_slice(&contents)?),
+        None => bail!(
+            "There is no generated message to regenerate a part of. Run gptcommit without --regenerate first."
+        ),
+    }
+}
+
+/// Keeps the parts of the message for a later `--regenerate`. The message is
+/// written even when they can't be kept.
+fn keep_message_parts(path: Option<&Path>, parts: &CommitMessageParts) {
+    let Some(path) = path else {
+        return;
+    };
+    let kept = serde_json::to_vec(parts)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(cache::write_locked(path, &contents)?));
+    if let Err(e) = kept {
+        warn!("could not keep the message for --regenerate: {e}");
+    }
+}
+
+/// Leaves the note of the per-file summaries for the post-commit hook to attach
+/// to the new commit. The message is written even when it can't be left.
+fn leave_pending_note(path: Option<&Path>, parts: &CommitMessageParts) {
+    let Some(path) = path.filter(|_| !parts.git_note.is_empty()) else {
+        return;
+    };
+    let left = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(path, &parts.git_note));
+    if let Err(e) = left {
+        warn!("could not leave the per-file summaries for the git note: {e}");
+    }
 }
-fn get_llm_client(settings: &Settings) -> Box<dyn LlmClient> {
+
+/// Writes the commit message as the exact bytes of its encoding, so non-ASCII
+/// text like emoji or CJK reaches git unchanged.
+fn write_commit_message(path: &Path, message: &str, encoding: OutputEncoding) -> Result<()> {
+    fs::write(path, encoding.encode(message))?;
+    Ok(())
+}
+
+/// The client of the configured model provider, or why it can't be created.
+pub(crate) fn try_get_llm_client(settings: &Settings) -> Result<Box<dyn LlmClient>> {
+    let proactive_throttle = settings
+        .http
+        .as_ref()
+        .and_then(|http| http.proactive_throttle)
+        .unwrap_or(false);
     match settings {
         Settings {
             model_provider: Some(ModelProvider::TesterFoobar),
             ..
-        } => Box::new(FooBarClient::new().unwrap()),
+        } => Ok(Box::new(FooBarClient::new()?)),
+        Settings {
+            model_provider: Some(ModelProvider::Mock),
+            mock,
+            ..
+        } => Ok(Box::new(MockClient::new(
+            mock.to_owned().unwrap_or_default(),
+        ))),
         Settings {
             model_provider: Some(ModelProvider::OpenAI),
             openai: Some(openai),
             ..
         } => {
-            let client = OpenAIClient::new(openai.to_owned());
-            if let Err(_e) = client {
-                print_help_openai_api_key();
-                panic!("OpenAI API key not found in config or environment");
+            let client = match OpenAIClient::new(openai.to_owned()) {
+                Ok(client) => client,
+                Err(e) if is_missing_api_key(&e) => {
+                    return Err(SummarizeError::MissingApiKey(
+                        "OpenAI API key not found in config or environment".to_string(),
+                    )
+                    .into())
+                }
+                Err(e) => bail!("Could not load the OpenAI client: {e}"),
+            };
+            let client = match embeddings_base_url(settings) {
+                Some(base_url) => client.with_embeddings_base_url(base_url),
+                None => client,
+            };
+            if proactive_throttle {
+                Ok(Box::new(client.with_proactive_throttle()))
+            } else {
+                Ok(Box::new(client))
             }
-            Box::new(client.unwrap())
         }
-        _ => panic!("Could not load LLM Client from config!"),
+        Settings {
+            model_provider: Some(ModelProvider::OpenRouter),
+            openrouter: Some(openrouter),
+            ..
+        } => match OpenRouterClient::new(openrouter.to_owned()) {
+            Ok(client) if proactive_throttle => Ok(Box::new(client.with_proactive_throttle())),
+            Ok(client) => Ok(Box::new(client)),
+            Err(e) if is_missing_api_key(&e) => Err(e),
+            Err(e) => bail!("Could not load the OpenRouter client: {e}"),
+        },
+        _ => bail!("Could not load LLM Client from config!"),
     }
 }
 
-pub(crate) async fn main(settings: Settings, args: PrepareCommitMsgArgs) -> Result<()> {
-    match (args.commit_source, settings.allow_amend) {
-        (CommitSource::Empty, _) | (CommitSource::Commit, Some(true)) | (CommitSource::Message, _) => {}
-        (CommitSource::Commit, _) => {
-            println!("🤖 Skipping gptcommit since we're amending a commit. Change this behavior with `gptcommit config set allow_amend true`");
-            return Ok(());
+/// The `model.embeddings_base_url`, if one is set.
+pub(crate) fn embeddings_base_url(settings: &Settings) -> Option<&str> {
+    settings
+        .model
+        .as_ref()
+        .and_then(|model| model.embeddings_base_url.as_deref())
+        .filter(|base_url| !base_url.is_empty())
+}
+
+/// The `git.skip_commit_sources` category that leaves this commit's message
+/// untouched, if any: the commit source itself, or `fixup` and `revert` for
+/// messages git already wrote, e.g. by `git commit --fixup` or `git revert`.
+fn skip_category(
+    commit_source: CommitSource,
+    message: &str,
+    skip_commit_sources: &[String],
+) -> Option<String> {
+    let first_line = message
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .unwrap_or_default();
+    let message_category = if ["fixup!", "squash!", "amend!"]
+        .iter()
+        .any(|prefix| first_line.starts_with(prefix))
+    {
+        Some("fixup")
+    } else if first_line.starts_with("Revert \"") {
+        Some("revert")
+    } else {
+        None
+    };
+    let source_category = commit_source
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .filter(|name| !name.is_empty());
+    source_category
+        .into_iter()
+        .chain(message_category.map(str::to_string))
+        .find(|category| skip_commit_sources.contains(category))
+}
+
+pub(crate) async fn main(settings: Settings, mut args: PrepareCommitMsgArgs) -> Result<()> {
+    args.last_message_file = git::get_git_path(LAST_MESSAGE_FILE)
+        .map_err(|e| debug!("no git directory to keep the message in: {e}"))
+        .ok();
+    args.pending_note_file = git::get_git_path(PENDING_NOTE_FILE).ok();
+    let cancellation = args.cancellation.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            cancellation.cancel();
         }
-        _ => {
-            println!(
-                "🤖 Skipping gptcommit because the githook isn't set up for the \"{}\" commit mode.", args.commit_source
-            );
-            return Ok(());
+        // a second Ctrl-C doesn't wait for a request that doesn't finish
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
         }
+    });
+    run(settings, args, try_get_llm_client).await
+}
+
+/// Generates the message, then handles a failure as `git.on_error` says.
+async fn run(
+    settings: Settings,
+    args: PrepareCommitMsgArgs,
+    get_llm_client: impl FnOnce(&Settings) -> Result<Box<dyn LlmClient>>,
+) -> Result<()> {
+    let on_error = match settings
+        .git
+        .as_ref()
+        .and_then(|git| git.on_error.as_deref())
+    {
+        Some(on_error) if !on_error.is_empty() => HookErrorAction::from_str(on_error)
+            .map_err(|_| anyhow!("Invalid git.on_error: {on_error}"))?,
+        _ => HookErrorAction::default(),
     };
+    let commit_msg_file = args.commit_msg_file.clone();
 
-    let client = get_llm_client(&settings);
-    let summarization_client = SummarizationClient::new(settings.to_owned(), client)?;
+    let Err(e) = generate_message(settings, args, get_llm_client).await else {
+        return Ok(());
+    };
+    if let Some(SummarizeError::Cancelled) = e.downcast_ref() {
+        return Err(e);
+    }
+    match on_error {
+        HookErrorAction::Abort => Err(e),
+        HookErrorAction::Skip => {
+            println!("🤖 gptcommit could not write the commit message, leaving it as is: {e}");
+            Ok(())
+        }
+        HookErrorAction::Empty => {
+            println!("🤖 gptcommit could not write the commit message, leaving it empty: {e}");
+            let original_message = fs::read_to_string(&commit_msg_file).unwrap_or_default();
+            fs::write(&commit_msg_file, empty_template(&original_message))?;
+            Ok(())
+        }
+    }
+}
 
-    println!(
-        "{}",
-        "🤖 Let's ask OpenAI to summarize those diffs! 🚀"
-            .green()
-            .bold()
-    );
+/// The message git wrote without anything but its comments, like the template
+/// of a commit without `-m`.
+fn empty_template(message: &str) -> String {
+    let comments = message
+        .lines()
+        .filter(|line| line.starts_with('#'))
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+    format!("\n{comments}")
+}
+
+async fn generate_message(
+    settings: Settings,
+    args: PrepareCommitMsgArgs,
+    get_llm_client: impl FnOnce(&Settings) -> Result<Box<dyn LlmClient>>,
+) -> Result<()> {
+    if let Some(pending_note_file) = args.pending_note_file.as_deref() {
+        // a note left by a commit that was aborted describes other changes, and
+        // must not reach this commit even when it is skipped
+        let _ = fs::remove_file(pending_note_file);
+    }
+
+    if args.commit_source == CommitSource::Commit && settings.allow_amend != Some(true) {
+        println!("🤖 Skipping gptcommit since we're amending a commit. Change this behavior with `gptcommit config set allow_amend true`");
+        return Ok(());
+    }
 
     let original_message: String = if args.commit_msg_file.is_file() {
         fs::read_to_string(&args.commit_msg_file)?
@@ -107,19 +513,1052 @@ pub(crate) async fn main(settings: Settings, args: PrepareCommitMsgArgs) -> Resu
         String::new()
     };
 
-    let output = if let Some(git_diff_output) = args.git_diff_content {
-        fs::read_to_string(git_diff_output)?
-    } else {
-        git::get_diffs()?
+    let mut skip_commit_sources = settings
+        .git
+        .as_ref()
+        .and_then(|git| git.skip_commit_sources.clone())
+        .unwrap_or_default();
+    let respect_git_template = settings
+        .output
+        .as_ref()
+        .and_then(|output| output.respect_git_template)
+        .unwrap_or(false);
+    if respect_git_template {
+        // the template is passed to the prompts instead
+        skip_commit_sources.retain(|source| source != "template");
+    }
+    if let Some(category) =
+        skip_category(args.commit_source, &original_message, &skip_commit_sources)
+    {
+        println!(
+            "🤖 Skipping gptcommit for this \"{category}\" commit. Change this behavior with `gptcommit config set git.skip_commit_sources`"
+        );
+        return Ok(());
+    }
+
+    let settings = apply_args_to_settings(settings, &args);
+    let encoding = match settings
+        .output
+        .as_ref()
+        .and_then(|output| output.encoding.as_deref())
+    {
+        Some(encoding) if !encoding.is_empty() => OutputEncoding::from_str(encoding)
+            .map_err(|_| anyhow!("Invalid output.encoding: {encoding}"))?,
+        _ => OutputEncoding::default(),
+    };
+    let offline_fallback = settings
+        .output
+        .as_ref()
+        .and_then(|output| output.offline_fallback)
+        .unwrap_or(false);
+    let (client, offline) = match get_llm_client(&settings) {
+        Ok(client) => (client, false),
+        Err(e) if offline_fallback && is_missing_api_key(&e) => {
+            warn!("{e}, writing a message from the changed files instead");
+            (Box::new(OfflineClient) as Box<dyn LlmClient>, true)
+        }
+        Err(e) => {
+            if matches!(settings.model_provider, Some(ModelProvider::OpenAI)) {
+                print_help_openai_api_key();
+            }
+            return Err(e);
+        }
     };
+    let mut summarization_client = SummarizationClient::new(settings.to_owned(), client)?
+        .with_cancellation(args.cancellation.clone());
+    let branch = git::get_current_branch().ok().flatten();
+    if branch.is_none() {
+        debug!("No branch checked out, skipping branch name features");
+    }
+    summarization_client = summarization_client.with_branch(branch);
+    let recent_commit_context = settings
+        .output
+        .as_ref()
+        .and_then(|output| output.recent_commit_context)
+        .unwrap_or(0) as usize;
+    if recent_commit_context > 0 {
+        match git::get_recent_commit_subjects(recent_commit_context) {
+            Ok(subjects) => {
+                summarization_client = summarization_client.with_recent_commits(&subjects);
+            }
+            Err(e) => debug!("No recent commits for the title prompt: {e}"),
+        }
+    }
+    if args.transcript {
+        summarization_client = summarization_client.with_transcript(ProgressWriter::stderr());
+    } else if io::stderr().is_terminal() {
+        summarization_client = summarization_client.with_progress(ProgressWriter::stderr());
+    }
+    if let Some(commit_type) = &args.commit_type {
+        summarization_client = summarization_client.with_commit_type(commit_type)?;
+    }
 
-    let file_diffs = output.split_prefix_inclusive("\ndiff --git ");
-    let commit_message = summarization_client.get_commit_message(file_diffs, &original_message).await?;
+    if let Some(part) = args.regenerate {
+        if offline {
+            bail!("--regenerate needs the model provider, which has no credentials");
+        }
+        let mut parts = load_message_parts(args.last_message_file.as_deref())?;
+        summarization_client.regenerate(&mut parts, part).await?;
+        let commit_message = summarization_client.render_commit_message(&parts).await?;
+        keep_message_parts(args.last_message_file.as_deref(), &parts);
+        leave_pending_note(args.pending_note_file.as_deref(), &parts);
+        write_commit_message(&args.commit_msg_file, &commit_message, encoding)?;
+        return Ok(());
+    }
 
-    fs::write(
-        &args.commit_msg_file,
-        commit_message,
-    )?;
+    if !offline {
+        println!(
+            "{}",
+            "🤖 Let's ask OpenAI to summarize those diffs! 🚀"
+                .green()
+                .bold()
+        );
+    }
+
+    let output = read_diff(&args, &mut io::stdin())?;
+
+    let file_diffs = select_file_diffs(
+        output.split_prefix_inclusive("\ndiff --git "),
+        &args.only,
+        &args.exclude,
+    );
+    let detect_wip = settings
+        .output
+        .as_ref()
+        .and_then(|output| output.detect_wip)
+        .unwrap_or(false);
+    let commit_message = if args.wip || (detect_wip && is_work_in_progress(&file_diffs)) {
+        summarization_client.get_wip_message(file_diffs)?
+    } else if offline {
+        summarization_client.get_offline_message(file_diffs)?
+    } else {
+        let commit_context = match &args.range {
+            Some(range) => {
+                range_context(&original_message, &git::get_range_commit_subjects(range)?)
+            }
+            None => original_message,
+        };
+        let parts = summarization_client
+            .get_commit_message_parts(file_diffs, &commit_context)
+            .await?;
+        keep_message_parts(args.last_message_file.as_deref(), &parts);
+        leave_pending_note(args.pending_note_file.as_deref(), &parts);
+        summarization_client.render_commit_message(&parts).await?
+    };
+
+    write_commit_message(&args.commit_msg_file, &commit_message, encoding)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::cli::{Action, GptcommitCLI};
+    use crate::llms::test_server::serve_once;
+
+    #[tokio::test]
+    async fn test_diff_from_stdin() {
+        let args = PrepareCommitMsgArgs {
+            commit_msg_file: PathBuf::from("COMMIT_EDITMSG"),
+            commit_source: CommitSource::Empty,
+            commit_sha: None,
+            diff_file: None,
+            diff_from_stdin: true,
+            include_unstaged: false,
+            range: None,
+            pathspecs: vec![],
+            only: vec![],
+            exclude: vec![],
+            co_authors: vec![],
+            wip: false,
+            regenerate: None,
+            deterministic: false,
+            lang: None,
+            commit_type: None,
+            transcript: false,
+            last_message_file: None,
+            pending_note_file: None,
+            cancellation: CancellationToken::new(),
+        };
+        let mut stdin = io::Cursor::new(
+            r#"diff --git a/foo.txt b/foo.txt
+index 257cc56..3bd1f0e 100644
+--- a/foo.txt
++++ b/foo.txt
+@@ -1 +1 @@
+-foo
++bar
+diff --git a/src/lib.rs b/src/lib.rs
+index 3bd1f0e..257cc56 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1 +1 @@
+-fn foo() {}
++fn bar() {}
+"#,
+        );
+        let diff = read_diff(&args, &mut stdin).unwrap();
+
+        let summarization_client = SummarizationClient::new(
+            Settings::new().unwrap(),
+            Box::new(FooBarClient::new().unwrap()),
+        )
+        .unwrap();
+        let summary_for_file = summarization_client
+            .get_file_summaries(diff.split_prefix_inclusive("\ndiff --git "), "")
+            .await;
+
+        assert_eq!(summary_for_file.len(), 2);
+        assert_eq!(summary_for_file["foo.txt"], "foo bar");
+        assert_eq!(summary_for_file["src/lib.rs"], "foo bar");
+    }
+
+    #[test]
+    fn test_explicit_diff_takes_precedence_over_include_unstaged() {
+        assert!(parse_args(&["--commit-source", "", "--working-tree"]).include_unstaged);
+        assert_eq!(
+            parse_args(&["--commit-source", "", "packages/web", "packages/api"]).pathspecs,
+            vec!["packages/web", "packages/api"]
+        );
+
+        let args = parse_args(&[
+            "--commit-source",
+            "",
+            "--include-unstaged",
+            "--diff-from-stdin",
+        ]);
+        let diff = read_diff(&args, &mut io::Cursor::new("diff --git a/x b/x\n")).unwrap();
+        assert_eq!(diff, "diff --git a/x b/x\n");
+    }
+
+    #[test]
+    fn test_empty_staged_amend_uses_commit_diff() {
+        let head_diff = "diff --git a/src/lib.rs b/src/lib.rs\n--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1 +1 @@\n-fn foo() {}\n+fn bar() {}\n";
+        let amend = parse_args(&["--commit-source", "commit", "--commit-sha", "HEAD"]);
+        let get_commit_diff = |commit: &str| {
+            assert_eq!(commit, "HEAD");
+            Ok(head_diff.to_string())
+        };
+
+        assert_eq!(
+            amend_fallback(&amend, "".to_string(), get_commit_diff).unwrap(),
+            head_diff
+        );
+        // staged changes and other commit sources keep the staged diff
+        assert_eq!(
+            amend_fallback(&amend, "staged".to_string(), get_commit_diff).unwrap(),
+            "staged"
+        );
+        let message = parse_args(&["--commit-source", "message"]);
+        assert_eq!(
+            amend_fallback(&message, "".to_string(), get_commit_diff).unwrap(),
+            ""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_output_encoding() {
+        let dir =
+            std::env::temp_dir().join(format!("gptcommit-encoding-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("changes.diff"),
+            "diff --git a/docs/guide.md b/docs/guide.md\nindex 1..2 100644\n--- a/docs/guide.md\n+++ b/docs/guide.md\n@@ -1 +1 @@\n-Old intro\n+新しい紹介\n",
+        )
+        .unwrap();
+        let args = || {
+            let diff_file = dir.join("changes.diff");
+            let mut args = parse_args(&[
+                "--commit-source",
+                "",
+                "--diff-file",
+                diff_file.to_str().unwrap(),
+            ]);
+            args.commit_msg_file = dir.join("COMMIT_EDITMSG");
+            args
+        };
+        let client = || MockClient::responding_with(|_| "✨ 日本語の紹介を追加".to_string());
+        let mut settings = Settings::new().unwrap();
+        settings.output.as_mut().unwrap().conventional_commit = Some(false);
+
+        run(settings.clone(), args(), |_| Ok(Box::new(client())))
+            .await
+            .unwrap();
+        let utf8 = fs::read(dir.join("COMMIT_EDITMSG")).unwrap();
+
+        settings.output.as_mut().unwrap().encoding = Some("utf-8-bom".to_string());
+        run(settings.clone(), args(), |_| Ok(Box::new(client())))
+            .await
+            .unwrap();
+        let utf8_bom = fs::read(dir.join("COMMIT_EDITMSG")).unwrap();
+
+        settings.output.as_mut().unwrap().encoding = Some("latin-1".to_string());
+        let err = run(settings, args(), |_| Ok(Box::new(client())))
+            .await
+            .unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // the emoji and CJK text reach the file as their UTF-8 bytes
+        assert!(String::from_utf8(utf8.clone())
+            .unwrap()
+            .starts_with("✨ 日本語の紹介を追加\n"));
+        assert_eq!(&utf8_bom[..3], b"\xef\xbb\xbf");
+        assert_eq!(&utf8_bom[3..], &utf8[..]);
+        assert!(err.to_string().contains("Invalid output.encoding: latin-1"));
+    }
+
+    #[test]
+    fn test_skip_category() {
+        let skip = vec![
+            "merge".to_string(),
+            "fixup".to_string(),
+            "revert".to_string(),
+        ];
+        assert_eq!(
+            skip_category(CommitSource::Merge, "Merge branch 'widget'", &skip).as_deref(),
+            Some("merge")
+        );
+        assert_eq!(
+            skip_category(CommitSource::Message, "squash! Add a widget\n", &skip).as_deref(),
+            Some("fixup")
+        );
+        assert_eq!(
+            skip_category(
+                CommitSource::Message,
+                "Revert \"Add a widget\"\n\nThis reverts commit 1234.",
+                &skip
+            )
+            .as_deref(),
+            Some("revert")
+        );
+        assert_eq!(
+            skip_category(CommitSource::Message, "Add a widget", &skip),
+            None
+        );
+        assert_eq!(skip_category(CommitSource::Empty, "", &skip), None);
+        assert_eq!(
+            skip_category(CommitSource::Merge, "Merge branch 'widget'", &[]),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_skipped_commits_make_no_llm_calls() {
+        for (name, source, message) in [
+            ("merge", "merge", "Merge branch 'widget'\n"),
+            ("fixup", "message", "fixup! Add a widget\n"),
+        ] {
+            let commit_msg_file = std::env::temp_dir()
+                .join(format!("gptcommit-skip-test-{}-{name}", std::process::id()));
+            fs::write(&commit_msg_file, message).unwrap();
+            let mut args = parse_args(&["--commit-source", source]);
+            args.commit_msg_file = commit_msg_file.clone();
+            let client = MockClient::default();
+            let prompts = client.prompts();
+
+            run(Settings::new().unwrap(), args, move |_| {
+                Ok(Box::new(client))
+            })
+            .await
+            .unwrap();
+
+            assert!(prompts.lock().unwrap().is_empty());
+            assert_eq!(fs::read_to_string(&commit_msg_file).unwrap(), message);
+            fs::remove_file(commit_msg_file).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_template_commits_with_respect_git_template() {
+        let dir =
+            std::env::temp_dir().join(format!("gptcommit-template-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let diff_file = dir.join("changes.diff");
+        fs::write(
+            &diff_file,
+            "diff --git a/src/lib.rs b/src/lib.rs\nindex 3bd1f0e..257cc56 100644\n--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1 +1 @@\n-fn foo() {}\n+fn bar() {}\n",
+        )
+        .unwrap();
+        let template = "Why: <reason>\n# Please enter the commit message\n";
+        let run_with = |respect_git_template: bool| {
+            let commit_msg_file = dir.join(format!("COMMIT_EDITMSG-{respect_git_template}"));
+            fs::write(&commit_msg_file, template).unwrap();
+            let mut args = parse_args(&[
+                "--commit-source",
+                "template",
+                "--diff-file",
+                diff_file.to_str().unwrap(),
+            ]);
+            args.commit_msg_file = commit_msg_file.clone();
+            let mut settings = Settings::new().unwrap();
+            settings.output.as_mut().unwrap().respect_git_template = Some(respect_git_template);
+            let client = MockClient::default();
+            let prompts = client.prompts();
+            async move {
+                run(settings, args, move |_| Ok(Box::new(client)))
+                    .await
+                    .unwrap();
+                let prompts = prompts.lock().unwrap().clone();
+                (fs::read_to_string(commit_msg_file).unwrap(), prompts)
+            }
+        };
+
+        // skipped as a `template` commit by default
+        let (message, prompts) = run_with(false).await;
+        assert_eq!(message, template);
+        assert!(prompts.is_empty());
+
+        let (message, prompts) = run_with(true).await;
+        fs::remove_dir_all(&dir).unwrap();
+        assert_ne!(message, template);
+        assert!(prompts
+            .iter()
+            .any(|prompt| prompt.contains("Why: <reason>")));
+    }
+
+    #[tokio::test]
+    async fn test_commit_type_skips_the_classifier() {
+        let dir = std::env::temp_dir().join(format!("gptcommit-type-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let diff_file = dir.join("changes.diff");
+        fs::write(
+            &diff_file,
+            "diff --git a/src/lib.rs b/src/lib.rs\nindex 3bd1f0e..257cc56 100644\n--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1 +1 @@\n-fn foo() {}\n+fn bar() {}\n",
+        )
+        .unwrap();
+        let mut args = parse_args(&[
+            "--commit-source",
+            "",
+            "--type",
+            "fix",
+            "--diff-file",
+            diff_file.to_str().unwrap(),
+        ]);
+        args.commit_msg_file = dir.join("COMMIT_EDITMSG");
+        let client = MockClient::default();
+        let prompts = client.prompts();
+
+        run(Settings::new().unwrap(), args, move |_| {
+            Ok(Box::new(client))
+        })
+        .await
+        .unwrap();
+
+        assert!(fs::read_to_string(dir.join("COMMIT_EDITMSG"))
+            .unwrap()
+            .starts_with("fix: "));
+        assert!(!prompts
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|p| p.contains("The label best describing this change")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Titles and bodies that change with every completion, numbered from 1.
+    fn respond_with_take(prompt: &str) -> String {
+        static TITLES: AtomicUsize = AtomicUsize::new(1);
+        static BODIES: AtomicUsize = AtomicUsize::new(1);
+        if prompt.starts_with("You are an expert programmer writing a commit message title.") {
+            format!("Rename foo, take {}", TITLES.fetch_add(1, Ordering::SeqCst))
+        } else if prompt.starts_with("You are an expert programmer writing a commit message.") {
+            format!(
+                "- Rename foo to bar, take {}",
+                BODIES.fetch_add(1, Ordering::SeqCst)
+            )
+        } else {
+            "- Rename foo to bar".to_string()
+        }
+    }
+
+    async fn run_in(dir: &Path, extra_args: &[&str]) -> (String, usize) {
+        let diff_file = dir.join("changes.diff");
+        let diff_file = diff_file.to_str().unwrap();
+        let mut args = parse_args(
+            &[
+                ["--commit-source", "", "--diff-file", diff_file].as_slice(),
+                extra_args,
+            ]
+            .concat(),
+        );
+        args.commit_msg_file = dir.join("COMMIT_EDITMSG");
+        args.last_message_file = Some(dir.join("last_message.json"));
+        let client = MockClient::responding_with(respond_with_take);
+        let prompts = client.prompts();
+
+        run(Settings::new().unwrap(), args, move |_| {
+            Ok(Box::new(client))
+        })
+        .await
+        .unwrap();
+
+        let message = fs::read_to_string(dir.join("COMMIT_EDITMSG")).unwrap();
+        let prompts = prompts.lock().unwrap().len();
+        (message, prompts)
+    }
+
+    #[tokio::test]
+    async fn test_regenerate_keeps_the_other_parts() {
+        let dir =
+            std::env::temp_dir().join(format!("gptcommit-regenerate-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("changes.diff"),
+            "diff --git a/src/lib.rs b/src/lib.rs\nindex 3bd1f0e..257cc56 100644\n--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1 +1 @@\n-fn foo() {}\n+fn bar() {}\n",
+        )
+        .unwrap();
+        let split = |message: &str| {
+            let (title, body) = message.split_once('\n').unwrap();
+            (title.to_string(), body.to_string())
+        };
+
+        let (message, _) = run_in(&dir, &[]).await;
+        let (title, body) = split(&message);
+        assert!(title.ends_with("Rename foo, take 1"));
+        assert!(body.contains("take 1"));
+
+        let (regenerated, prompts) = run_in(&dir, &["--regenerate", "title"]).await;
+        let (regenerated_title, regenerated_body) = split(&regenerated);
+        assert_eq!(prompts, 1);
+        assert!(regenerated_title.ends_with("Rename foo, take 2"));
+        assert_eq!(regenerated_body, body);
+
+        let (regenerated, prompts) = run_in(&dir, &["--regenerate", "body"]).await;
+        let (title, body) = split(&regenerated);
+        assert_eq!(prompts, 1);
+        assert_eq!(title, regenerated_title);
+        assert!(body.contains("take 2"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut args = parse_args(&["--commit-source", "", "--regenerate", "title"]);
+        args.last_message_file = Some(dir.join("last_message.json"));
+        let err = run(Settings::new().unwrap(), args, |_| {
+            Ok(Box::new(MockClient::default()))
+        })
+        .await
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("There is no generated message to regenerate"));
+    }
+
+    #[tokio::test]
+    async fn test_offline_fallback_without_an_api_key() {
+        let dir =
+            std::env::temp_dir().join(format!("gptcommit-offline-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("changes.diff"),
+            "diff --git a/docs/guide.md b/docs/guide.md\nindex 1..2 100644\n--- a/docs/guide.md\n+++ b/docs/guide.md\n@@ -1,2 +1,3 @@\n-Old intro\n+New intro\n+More detail\n same\ndiff --git a/docs/faq.md b/docs/faq.md\nnew file mode 100644\nindex 0000000..3\n--- /dev/null\n+++ b/docs/faq.md\n@@ -0,0 +1 @@\n+Questions\n",
+        )
+        .unwrap();
+        let args = || {
+            let diff_file = dir.join("changes.diff");
+            let mut args = parse_args(&[
+                "--commit-source",
+                "",
+                "--diff-file",
+                diff_file.to_str().unwrap(),
+            ]);
+            args.commit_msg_file = dir.join("COMMIT_EDITMSG");
+            args
+        };
+        let mut settings = Settings::new().unwrap();
+        settings.model_provider = Some(ModelProvider::OpenAI);
+        settings.openai.as_mut().unwrap().api_key = None;
+        settings.git.as_mut().unwrap().branch_ticket_regex = Some("".to_string());
+
+        let err = run(settings.clone(), args(), try_get_llm_client)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("API key not found"));
+
+        settings.output.as_mut().unwrap().offline_fallback = Some(true);
+        run(settings.clone(), args(), try_get_llm_client)
+            .await
+            .unwrap();
+        let message = fs::read_to_string(dir.join("COMMIT_EDITMSG")).unwrap();
+
+        // only a missing key falls back, not a client that is misconfigured otherwise
+        settings.model_provider = Some(ModelProvider::OpenRouter);
+        let openrouter = settings.openrouter.get_or_insert_with(Default::default);
+        openrouter.api_key = Some("sk-or-test".to_string());
+        openrouter.model = Some("".to_string());
+        let err = run(settings, args(), try_get_llm_client).await.unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            err.to_string().contains("No OpenRouter model configured"),
+            "{err}"
+        );
+        assert_eq!(
+            message,
+            "docs: Update docs\n\n- docs/guide.md (+2, -1)\n- docs/faq.md (+1, -0)\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_per_file_summaries_in_git_notes() {
+        let repo = crate::actions::install::tests::temp_repo("git-notes");
+        fs::write(repo.join("a.txt"), "a\n").unwrap();
+        fs::write(repo.join("b.txt"), "b\n").unwrap();
+        let git = |args: &[&str]| crate::cmd::run_command_in(&repo, "git", args).unwrap();
+        git(&["config", "user.name", "gptcommit"]);
+        git(&["config", "user.email", "gptcommit@example.com"]);
+        git(&["add", "."]);
+        let diff = git(&["diff", "--cached"]);
+        fs::write(repo.join(".git/changes.diff"), diff).unwrap();
+
+        let diff_file = repo.join(".git/changes.diff");
+        let mut args = parse_args(&[
+            "--commit-source",
+            "",
+            "--diff-file",
+            diff_file.to_str().unwrap(),
+        ]);
+        args.commit_msg_file = repo.join(".git/COMMIT_EDITMSG");
+        let pending_note_file = repo.join(".git").join(PENDING_NOTE_FILE);
+        args.pending_note_file = Some(pending_note_file.clone());
+        let mut settings = Settings::new().unwrap();
+        settings
+            .output
+            .as_mut()
+            .unwrap()
+            .store_per_file_in_git_notes = Some(true);
+        run(settings, args, |_| Ok(Box::new(MockClient::default())))
+            .await
+            .unwrap();
+        assert!(pending_note_file.exists());
+
+        git(&["commit", "--quiet", "--file", ".git/COMMIT_EDITMSG"]);
+        crate::actions::post_commit::add_pending_note(&repo, &pending_note_file).unwrap();
+
+        let note = git(&["notes", "--ref", "gptcommit", "show", "HEAD"]);
+        fs::remove_dir_all(&repo).unwrap();
+        assert!(!pending_note_file.exists());
+        let a = note.find("- Update a.txt").unwrap();
+        let b = note.find("- Update b.txt").unwrap();
+        assert!(a < b);
+    }
+
+    /// A summary listing the points of every file.
+    fn respond_with_every_file(prompt: &str) -> String {
+        if let Some((_, file_diff)) = prompt.rsplit_once("THE GIT DIFF TO BE SUMMARIZED:") {
+            let file_name = util::get_file_name_from_diff(file_diff).unwrap_or_default();
+            format!("- Update {file_name}")
+        } else if prompt.contains("THE COMMIT MESSAGE TITLE:") {
+            "Add the language front end".to_string()
+        } else if prompt.contains("The label best describing this change:") {
+            "feat".to_string()
+        } else {
+            prompt
+                .lines()
+                .filter(|line| line.starts_with("- Update "))
+                .map(|line| format!("{line}\n"))
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_range_is_summarized_into_one_message() {
+        let repo = crate::actions::install::tests::temp_repo("range");
+        let git = |args: &[&str]| crate::cmd::run_command_in(&repo, "git", args).unwrap();
+        git(&["config", "user.name", "gptcommit"]);
+        git(&["config", "user.email", "gptcommit@example.com"]);
+        git(&["commit", "--quiet", "--allow-empty", "-m", "Initial commit"]);
+        git(&["tag", "base"]);
+        for (file, subject) in [
+            ("parser.rs", "Add the parser"),
+            ("lexer.rs", "Add the lexer"),
+            ("grammar.md", "Document the grammar"),
+        ] {
+            fs::write(repo.join(file), format!("{subject}\n")).unwrap();
+            git(&["add", file]);
+            git(&["commit", "--quiet", "-m", subject]);
+        }
+
+        let diff = git::get_range_diffs_in(&repo, "base..HEAD", &[]).unwrap();
+        let subjects = git::get_range_commit_subjects_in(&repo, "base..HEAD").unwrap();
+        fs::remove_dir_all(&repo).unwrap();
+        assert_eq!(
+            subjects,
+            vec!["Add the parser", "Add the lexer", "Document the grammar"]
+        );
+        let context = range_context("", &subjects);
+        assert_eq!(
+            context,
+            "Commits being squashed:\n- Add the parser\n- Add the lexer\n- Document the grammar\n"
+        );
+
+        let client = MockClient::responding_with(respond_with_every_file);
+        let prompts = client.prompts();
+        let message = SummarizationClient::new(Settings::new().unwrap(), Box::new(client))
+            .unwrap()
+            .get_commit_message(diff.split_prefix_inclusive("\ndiff --git "), &context)
+            .await
+            .unwrap();
+        for file in ["parser.rs", "lexer.rs", "grammar.md"] {
+            assert!(message.contains(file), "{file} missing from {message}");
+        }
+        assert!(prompts
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|prompt| prompt.contains("- Document the grammar")));
+
+        assert!(
+            parse_args(&["--commit-source", "", "--range", "base..HEAD"])
+                .range
+                .is_some()
+        );
+        assert!(GptcommitCLI::try_parse_from([
+            "gptcommit",
+            "prepare-commit-msg",
+            "--commit-msg-file",
+            "COMMIT_EDITMSG",
+            "--commit-source",
+            "",
+            "--range",
+            "base"
+        ])
+        .is_err());
+    }
+
+    /// Set for the run of [`test_range_is_diffed_from_the_merge_base`] inside
+    /// its repository, as `--range` reads the repository of the working directory.
+    const RANGE_REPO_ENV: &str = "GPTCOMMIT_TEST_RANGE_REPO";
+
+    #[tokio::test]
+    async fn test_range_is_diffed_from_the_merge_base() {
+        if std::env::var_os(RANGE_REPO_ENV).is_some() {
+            let args = parse_args(&["--commit-source", "", "--range", "main..feature"]);
+            run(Settings::new().unwrap(), args, |_| {
+                Ok(Box::new(MockClient::responding_with(
+                    respond_with_every_file,
+                )))
+            })
+            .await
+            .unwrap();
+            let message = fs::read_to_string("COMMIT_EDITMSG").unwrap();
+            assert!(message.contains("feature.rs"), "{message}");
+            // committed on main after the branch diverged, not reverted by it
+            assert!(!message.contains("hotfix.rs"), "{message}");
+            return;
+        }
+
+        let repo = crate::actions::install::tests::temp_repo("range-merge-base");
+        let git = |args: &[&str]| crate::cmd::run_command_in(&repo, "git", args).unwrap();
+        git(&["config", "user.name", "gptcommit"]);
+        git(&["config", "user.email", "gptcommit@example.com"]);
+        git(&["checkout", "--quiet", "-b", "main"]);
+        git(&["commit", "--quiet", "--allow-empty", "-m", "Initial commit"]);
+        git(&["checkout", "--quiet", "-b", "feature"]);
+        fs::write(repo.join("feature.rs"), "fn feature() {}\n").unwrap();
+        git(&["add", "feature.rs"]);
+        git(&["commit", "--quiet", "-m", "Add the feature"]);
+        git(&["checkout", "--quiet", "main"]);
+        fs::write(repo.join("hotfix.rs"), "fn hotfix() {}\n").unwrap();
+        git(&["add", "hotfix.rs"]);
+        git(&["commit", "--quiet", "-m", "Fix the release"]);
+
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "actions::prepare_commit_msg::tests::test_range_is_diffed_from_the_merge_base",
+                "--nocapture",
+            ])
+            .env(RANGE_REPO_ENV, "1")
+            .current_dir(&repo)
+            .output()
+            .unwrap();
+        fs::remove_dir_all(&repo).unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_amend_after_an_aborted_commit_drops_the_pending_note() {
+        let dir =
+            std::env::temp_dir().join(format!("gptcommit-stale-note-test-{}", std::process::id()));
+        // left by a commit whose message was generated, then aborted
+        let pending_note_file = dir.join(PENDING_NOTE_FILE);
+        fs::create_dir_all(pending_note_file.parent().unwrap()).unwrap();
+        fs::write(&pending_note_file, "- Update a.txt\n").unwrap();
+
+        let mut args = parse_args(&["--commit-source", "commit", "--commit-sha", "HEAD"]);
+        args.commit_msg_file = dir.join("COMMIT_EDITMSG");
+        args.pending_note_file = Some(pending_note_file.clone());
+        run(Settings::new().unwrap(), args, |_| {
+            Ok(Box::new(MockClient::default()))
+        })
+        .await
+        .unwrap();
+
+        let left_behind = pending_note_file.exists();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!left_behind);
+    }
+
+    #[tokio::test]
+    async fn test_on_error_with_a_failing_client() {
+        let dir =
+            std::env::temp_dir().join(format!("gptcommit-on-error-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let original_message =
+            "Fix the parser\n# Please enter the commit message for your changes.\n";
+        let run_with = |on_error: &str| {
+            let mut args = parse_args(&["--commit-source", ""]);
+            args.commit_msg_file = dir.join("COMMIT_EDITMSG");
+            fs::write(&args.commit_msg_file, original_message).unwrap();
+            let mut settings = Settings::new().unwrap();
+            settings.git.as_mut().unwrap().on_error = Some(on_error.to_string());
+            run(settings, args, |_| Err(anyhow!("connection refused")))
+        };
+        let message = || fs::read_to_string(dir.join("COMMIT_EDITMSG")).unwrap();
+
+        let err = run_with("abort").await.unwrap_err();
+        assert_eq!(err.to_string(), "connection refused");
+        assert_eq!(message(), original_message);
+
+        run_with("skip").await.unwrap();
+        assert_eq!(message(), original_message);
+
+        run_with("empty").await.unwrap();
+        assert_eq!(
+            message(),
+            "\n# Please enter the commit message for your changes.\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_commit_type_is_rejected() {
+        let args = parse_args(&["--commit-source", "", "--type", "Feature"]);
+        let err = run(Settings::new().unwrap(), args, |_| {
+            Ok(Box::new(MockClient::default()))
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("Invalid commit type: feature."));
+
+        // the types come from output.allowed_commit_types
+        let mut settings = Settings::new().unwrap();
+        settings.output.as_mut().unwrap().allowed_commit_types =
+            Some(vec!["feat".to_string(), "release".to_string()]);
+        let client = SummarizationClient::new(settings, Box::<MockClient>::default()).unwrap();
+        assert!(client.clone().with_commit_type("release").is_ok());
+        let err = client.with_commit_type("fix").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Invalid commit type: fix. Expected one of feat, release."));
+    }
+
+    #[tokio::test]
+    async fn test_model_override_reaches_client() {
+        let (api_base, request) = serve_once(
+            200,
+            &[],
+            r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-4","choices":[{"index":0,"message":{"role":"assistant","content":"- Change something"},"finish_reason":"stop"}]}"#,
+        )
+        .await;
+        let cli = GptcommitCLI::try_parse_from([
+            "gptcommit",
+            "--model",
+            "gpt-4",
+            "prepare-commit-msg",
+            "--commit-msg-file",
+            "COMMIT_EDITMSG",
+            "--commit-source",
+            "",
+        ])
+        .unwrap();
+        let config_paths = [
+            crate::settings::get_user_config_path(),
+            crate::settings::get_local_config_path(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+        let read_configs = || {
+            config_paths
+                .iter()
+                .map(|path| fs::read_to_string(path).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let original_configs = read_configs();
+        let mut settings = Settings::new().unwrap();
+        let openai = settings.openai.as_mut().unwrap();
+        openai.api_base = Some(api_base);
+        openai.api_key = Some("sk-test".to_string());
+        openai.retries = Some(0);
+        let configured_model = openai.model.clone();
+
+        let settings = settings.with_model_override(cli.model.as_deref());
+        try_get_llm_client(&settings)
+            .unwrap()
+            .completions("hello")
+            .await
+            .unwrap();
+
+        assert_eq!(request.await.unwrap().json()["model"], "gpt-4");
+        assert_ne!(configured_model.as_deref(), Some("gpt-4"));
+        // the override is for this run only, the config files are left as they were
+        assert!(!config_paths.is_empty());
+        assert_eq!(read_configs(), original_configs);
+    }
+
+    fn parse_args(args: &[&str]) -> PrepareCommitMsgArgs {
+        let cli = GptcommitCLI::try_parse_from(
+            [
+                "gptcommit",
+                "prepare-commit-msg",
+                "--commit-msg-file",
+                "COMMIT_EDITMSG",
+            ]
+            .iter()
+            .chain(args),
+        )
+        .unwrap();
+        match cli.action {
+            Action::PrepareCommitMsg(args) => *args,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_co_author_trailers() {
+        let args = parse_args(&[
+            "--commit-source",
+            "",
+            "--co-author",
+            "Ada Lovelace <ada@example.com>",
+            "--co-author",
+            "Alan Turing <alan@example.com>",
+            "--co-author",
+            "Ada Lovelace <ada@example.com>",
+        ]);
+        let settings = apply_args_to_settings(Settings::new().unwrap(), &args);
+        let summarization_client =
+            SummarizationClient::new(settings, Box::new(FooBarClient::new().unwrap())).unwrap();
+
+        let message = summarization_client
+            .get_commit_message(vec![], "")
+            .await
+            .unwrap();
+
+        let (body, trailers) = message.rsplit_once("\n\n").unwrap();
+        assert!(body.starts_with("foo bar"));
+        let trailers = trailers.lines().collect::<Vec<_>>();
+        let co_authors = &trailers[trailers.len() - 2..];
+        assert_eq!(
+            co_authors,
+            [
+                "Co-authored-by: Ada Lovelace <ada@example.com>",
+                "Co-authored-by: Alan Turing <alan@example.com>",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_only_and_exclude_globs() {
+        let file_diffs = ["src/main.rs", "src/llms/mock.rs", "README.md", "docs/usage.md"]
+            .map(|file_name| {
+                format!(
+                    "diff --git a/{file_name} b/{file_name}\n--- a/{file_name}\n+++ b/{file_name}\n@@ -1 +1 @@\n-foo\n+bar\n"
+                )
+            });
+        let file_diffs = file_diffs.iter().map(String::as_str).collect::<Vec<_>>();
+
+        let args = parse_args(&["--commit-source", "", "--only", "src/**"]);
+        let selected = select_file_diffs(file_diffs.clone(), &args.only, &args.exclude);
+        let summarization_client = SummarizationClient::new(
+            Settings::new().unwrap(),
+            Box::new(FooBarClient::new().unwrap()),
+        )
+        .unwrap();
+        let summary_for_file = summarization_client.get_file_summaries(selected, "").await;
+        let mut file_names = summary_for_file.keys().collect::<Vec<_>>();
+        file_names.sort();
+        assert_eq!(file_names, ["src/llms/mock.rs", "src/main.rs"]);
+
+        // excluding takes precedence over selecting
+        let args = parse_args(&[
+            "--commit-source",
+            "",
+            "--only",
+            "src/**",
+            "--only",
+            "*.md",
+            "--exclude",
+            "src/llms/**",
+        ]);
+        let selected = select_file_diffs(file_diffs.clone(), &args.only, &args.exclude);
+        assert_eq!(
+            selected
+                .iter()
+                .filter_map(|file_diff| util::get_file_name_from_diff(file_diff))
+                .collect::<Vec<_>>(),
+            ["src/main.rs", "README.md", "docs/usage.md"]
+        );
+
+        let args = parse_args(&["--commit-source", "", "--exclude", "docs/*"]);
+        assert_eq!(
+            select_file_diffs(file_diffs, &args.only, &args.exclude).len(),
+            3
+        );
+
+        let err = GptcommitCLI::try_parse_from([
+            "gptcommit",
+            "prepare-commit-msg",
+            "--commit-msg-file",
+            "COMMIT_EDITMSG",
+            "--commit-source",
+            "",
+            "--only",
+            "src/[",
+        ])
+        .unwrap_err();
+        assert!(err.to_string().contains("Invalid glob: src/["));
+    }
+
+    #[test]
+    fn test_invalid_co_author() {
+        let args = parse_args(&["--commit-source", "", "--co-author", "Ada Lovelace"]);
+        let settings = apply_args_to_settings(Settings::new().unwrap(), &args);
+        assert!(
+            SummarizationClient::new(settings, Box::new(FooBarClient::new().unwrap())).is_err()
+        );
+    }
+
+    #[test]
+    fn test_lang_override() {
+        let args = parse_args(&["--commit-source", "", "--lang", "ja"]);
+        let settings = apply_args_to_settings(Settings::new().unwrap(), &args);
+        assert_eq!(settings.output.unwrap().lang.as_deref(), Some("ja"));
+        let args = parse_args(&["--commit-source", "", "--lang", "auto,en"]);
+        let settings = apply_args_to_settings(Settings::new().unwrap(), &args);
+        assert_eq!(settings.output.unwrap().lang.as_deref(), Some("auto,en"));
+
+        let cli = GptcommitCLI::try_parse_from([
+            "gptcommit",
+            "prepare-commit-msg",
+            "--commit-msg-file",
+            "COMMIT_EDITMSG",
+            "--commit-source",
+            "",
+            "--lang",
+            "klingon",
+        ]);
+        assert_eq!(
+            cli.unwrap_err().kind(),
+            clap::error::ErrorKind::ValueValidation
+        );
+    }
+}
@@ -1,9 +1,26 @@
-use std::fs::{self};
+use std::{
+    fs::{self},
+    path::Path,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use colored::Colorize;
 
-use crate::{cmd::find_executable, git::get_hooks_path, settings::Settings};
+use crate::{
+    actions::install::{HOOK_BEGIN, HOOK_END},
+    cmd::find_executable,
+    git::get_hooks_path,
+    settings::Settings,
+};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum UninstallOutcome {
+    /// The hook only ran gptcommit and was deleted.
+    Removed,
+    /// The gptcommit block was removed, the rest of the hook is kept.
+    BlockRemoved,
+    NotInstalled,
+}
 
 pub(crate) async fn main(_settings: Settings) -> Result<()> {
     println!("{}", "Uninstalling gptcommit hook...".green().bold());
@@ -17,28 +34,128 @@ pub(crate) async fn main(_settings: Settings) -> Result<()> {
         "Found git hooks path for current git repo {}",
         hooks_path.display()
     );
-    let prepare_commit_msg_path = hooks_path.join("prepare-commit-msg");
-    info!("Removing file at {}", prepare_commit_msg_path.display());
-
-    if prepare_commit_msg_path.exists() {
-        let file_contents = fs::read_to_string(&prepare_commit_msg_path)?;
-        if file_contents == include_str!("../../prepare-commit-msg") {
-            fs::remove_file(&prepare_commit_msg_path)?;
-            println!(
-                "{}",
-                "gptcommit hook successfully uninstalled!".green().bold(),
-            );
-        } else {
-            warn!(
-                "{} is not gptcommit's prepare-commit-msg hook. Skipping uninstall.",
-                prepare_commit_msg_path.display()
-            );
-            warn!(
-                "Manually delete this file with\n  rm -rf {}",
-                prepare_commit_msg_path.display()
-            );
-        }
+
+    match uninstall_hook(&hooks_path)? {
+        UninstallOutcome::Removed | UninstallOutcome::BlockRemoved => println!(
+            "{}",
+            "gptcommit hook successfully uninstalled!".green().bold(),
+        ),
+        UninstallOutcome::NotInstalled => warn!(
+            "{} does not run gptcommit. Skipping uninstall.",
+            hooks_path.join("prepare-commit-msg").display()
+        ),
+    }
+    if uninstall_post_commit_hook(&hooks_path)? != UninstallOutcome::NotInstalled {
+        println!(
+            "{}",
+            "gptcommit post-commit hook successfully uninstalled!"
+                .green()
+                .bold(),
+        );
     }
 
     Ok(())
 }
+
+/// Removes the gptcommit block from the prepare-commit-msg hook in
+/// `hooks_path`, deleting the hook when nothing but the shebang is left.
+pub(crate) fn uninstall_hook(hooks_path: &Path) -> Result<UninstallOutcome> {
+    uninstall_hook_script(&hooks_path.join("prepare-commit-msg"))
+}
+
+/// Removes the gptcommit block from the post-commit hook in `hooks_path`, the
+/// way [`uninstall_hook`] does.
+pub(crate) fn uninstall_post_commit_hook(hooks_path: &Path) -> Result<UninstallOutcome> {
+    uninstall_hook_script(&hooks_path.join("post-commit"))
+}
+
+fn uninstall_hook_script(hook_path: &Path) -> Result<UninstallOutcome> {
+    if !hook_path.exists() {
+        return Ok(UninstallOutcome::NotInstalled);
+    }
+    let contents = fs::read_to_string(hook_path)?;
+    let Some(start) = contents.find(HOOK_BEGIN) else {
+        return Ok(UninstallOutcome::NotInstalled);
+    };
+    let Some(end) = contents[start..].find(HOOK_END) else {
+        bail!(
+            "{} has no `{HOOK_END}` line, remove the gptcommit block manually",
+            hook_path.display()
+        );
+    };
+    let before = contents[..start].trim_end();
+    let after = contents[start + end + HOOK_END.len()..].trim_start_matches('\n');
+
+    if before
+        .lines()
+        .all(|line| line.starts_with("#!") || line.trim().is_empty())
+        && after.trim().is_empty()
+    {
+        info!("Removing file at {}", hook_path.display());
+        fs::remove_file(hook_path)?;
+        return Ok(UninstallOutcome::Removed);
+    }
+    info!("Removing the gptcommit block from {}", hook_path.display());
+    fs::write(hook_path, format!("{before}\n{after}"))?;
+    Ok(UninstallOutcome::BlockRemoved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        actions::install::{install_hook, tests::temp_repo},
+        git::get_hooks_path_in,
+    };
+
+    #[test]
+    fn test_uninstall_fresh_install() {
+        let repo = temp_repo("uninstall-fresh");
+        let hooks_path = get_hooks_path_in(&repo).unwrap();
+        install_hook(&hooks_path).unwrap();
+
+        assert_eq!(
+            uninstall_hook(&hooks_path).unwrap(),
+            UninstallOutcome::Removed
+        );
+        assert!(!hooks_path.join("prepare-commit-msg").exists());
+        assert_eq!(
+            uninstall_hook(&hooks_path).unwrap(),
+            UninstallOutcome::NotInstalled
+        );
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_uninstall_keeps_existing_hook() {
+        let repo = temp_repo("uninstall-existing");
+        let hooks_path = get_hooks_path_in(&repo).unwrap();
+        let existing = "#!/bin/sh\necho 'Signed-off-by: me' >> \"$1\"\n";
+        fs::write(hooks_path.join("prepare-commit-msg"), existing).unwrap();
+        install_hook(&hooks_path).unwrap();
+
+        assert_eq!(
+            uninstall_hook(&hooks_path).unwrap(),
+            UninstallOutcome::BlockRemoved
+        );
+        assert_eq!(
+            fs::read_to_string(hooks_path.join("prepare-commit-msg")).unwrap(),
+            existing
+        );
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_uninstall_skips_foreign_hook() {
+        let repo = temp_repo("uninstall-foreign");
+        let hooks_path = get_hooks_path_in(&repo).unwrap();
+        fs::write(hooks_path.join("prepare-commit-msg"), "#!/bin/sh\n").unwrap();
+
+        assert_eq!(
+            uninstall_hook(&hooks_path).unwrap(),
+            UninstallOutcome::NotInstalled
+        );
+        assert!(hooks_path.join("prepare-commit-msg").exists());
+        fs::remove_dir_all(repo).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The tokens of the prompts and completions of one run, counted against
+/// `limits.max_total_tokens`. Clones share the count, so concurrent requests
+/// draw from the same budget.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TokenBudget {
+    used: Arc<AtomicUsize>,
+    refused: Arc<AtomicUsize>,
+    /// The most tokens the run may use, 0 for no limit
+    limit: usize,
+    /// The most tokens the run may have used after a request of this stage
+    ceiling: usize,
+}
+
+impl TokenBudget {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            ceiling: limit,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn is_limited(&self) -> bool {
+        self.limit > 0
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// The number of requests that were not sent because they didn't fit the budget.
+    pub(crate) fn refused(&self) -> usize {
+        self.refused.load(Ordering::SeqCst)
+    }
+
+    /// The same budget for the per-file summaries, which leave a quarter of
+    /// the limit to the title, summary and prefix of the commit.
+    pub(crate) fn for_file_summaries(&self) -> Self {
+        Self {
+            ceiling: self.limit - self.limit / 4,
+            ..self.clone()
+        }
+    }
+
+    /// Counts the tokens of a request about to be sent. Returns false, sending
+    /// nothing, when they would exceed the budget.
+    pub(crate) fn try_spend(&self, tokens: usize) -> bool {
+        if !self.is_limited() {
+            self.spend(tokens);
+            return true;
+        }
+        let spent = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                (used + tokens <= self.ceiling).then_some(used + tokens)
+            })
+            .is_ok();
+        if !spent {
+            self.refused.fetch_add(1, Ordering::SeqCst);
+        }
+        spent
+    }
+
+    /// Counts tokens already spent, e.g. those of a received completion.
+    pub(crate) fn spend(&self, tokens: usize) {
+        self.used.fetch_add(tokens, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_budget() {
+        let budget = TokenBudget::new(100);
+        let file_budget = budget.for_file_summaries();
+
+        assert!(file_budget.try_spend(50));
+        assert!(!file_budget.try_spend(30));
+        assert!(file_budget.try_spend(25));
+        assert_eq!(budget.used(), 75);
+        assert_eq!(budget.refused(), 1);
+
+        // the rest of the limit is left to the commit summary
+        assert!(budget.try_spend(25));
+        assert!(!budget.try_spend(1));
+        assert_eq!(budget.refused(), 2);
+
+        let unlimited = TokenBudget::new(0);
+        assert!(unlimited.for_file_summaries().try_spend(1_000_000));
+        assert_eq!(unlimited.used(), 1_000_000);
+    }
+}
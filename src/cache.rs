@@ -0,0 +1,90 @@
+//! File access for on-disk caches shared between gptcommit processes.
+//!
+//! Several hooks can run at once (e.g. in parallel worktrees), so every
+//! cache read and write holds an advisory lock on the cache file.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use fs2::FileExt;
+
+/// Replaces the contents of the cache file while holding an exclusive lock.
+pub(crate) fn write_locked(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    FileExt::lock_exclusive(&file)?;
+    let result = file
+        .set_len(0)
+        .and_then(|_| file.write_all(contents))
+        .and_then(|_| file.sync_data());
+    FileExt::unlock(&file)?;
+    result
+}
+
+/// Reads the cache file while holding a shared lock, or None if it doesn't exist yet.
+pub(crate) fn read_locked(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    FileExt::lock_shared(&file)?;
+    let mut contents = vec![];
+    let result = file.read_to_end(&mut contents);
+    FileExt::unlock(&file)?;
+    result.map(|_| Some(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_concurrent_writers_do_not_corrupt_the_cache() {
+        let dir = std::env::temp_dir().join(format!("gptcommit-cache-test-{}", std::process::id()));
+        let path = dir.join("cache.json");
+        let entries = [vec![b'a'; 256 * 1024], vec![b'b'; 128 * 1024]];
+
+        let writers = entries
+            .iter()
+            .cloned()
+            .map(|contents| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        write_locked(&path, &contents).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..20 {
+            if let Some(contents) = read_locked(&path).unwrap() {
+                assert!(contents.is_empty() || entries.contains(&contents));
+            }
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let contents = read_locked(&path).unwrap().unwrap();
+        assert!(entries.contains(&contents));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_missing_cache() {
+        let path = std::env::temp_dir().join("gptcommit-cache-test-missing.json");
+        assert_eq!(read_locked(&path).unwrap(), None);
+    }
+}
@@ -12,6 +12,12 @@ pub(crate) struct GptcommitCLI {
     /// Enable verbose logging.
     #[arg(short, long, global = true)]
     pub verbose: bool,
+    /// Print each prompt and completion to stderr.
+    #[arg(long, global = true)]
+    pub transcript: bool,
+    /// Override the configured model for this run.
+    #[arg(long, global = true)]
+    pub model: Option<String>,
 }
 
 /// Actions the application can perform.
@@ -23,6 +29,10 @@ pub(crate) enum Action {
     Uninstall,
     /// Read and modify settings
     Config(ConfigArgs),
+    /// Check the settings, prompts and model provider
+    Doctor,
     /// Run on the prepare-commit-msg hook
-    PrepareCommitMsg(PrepareCommitMsgArgs),
+    PrepareCommitMsg(Box<PrepareCommitMsgArgs>),
+    /// Run on the post-commit hook
+    PostCommit,
 }
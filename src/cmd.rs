@@ -1,13 +1,18 @@
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Result;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use which::which;
 /// Runs the command with the given arguments and returns its stdout if the command
 /// exits successfully. If the command fails, returns an error.
 pub(crate) fn run_command(cmd: &str, args: &[&str]) -> Result<String> {
-    let output = Command::new(cmd).args(args).output()?;
+    run_command_in(Path::new("."), cmd, args)
+}
+
+/// Runs the command like [`run_command`], from the directory `dir`.
+pub(crate) fn run_command_in(dir: &Path, cmd: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(cmd).args(args).current_dir(dir).output()?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
@@ -0,0 +1,556 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use strum_macros::Display;
+
+use crate::util::SplitPrefixInclusive;
+
+lazy_static! {
+    static ref HUNK_HEADER_REGEX: Regex =
+        Regex::new(r"^@@ -(\d+)(?:,(\d+))? \+(\d+)(?:,(\d+))? @@(.*)$").unwrap();
+}
+
+/// Splits a single file diff into its header (everything before the first
+/// `@@` hunk marker) and its hunks.
+pub(crate) fn split_hunks(file_diff: &str) -> (&str, Vec<&str>) {
+    let Some(first_hunk) = file_diff
+        .match_indices("@@ ")
+        .map(|(idx, _)| idx)
+        .find(|&idx| idx == 0 || file_diff.as_bytes()[idx - 1] == b'\n')
+    else {
+        return (file_diff, vec![]);
+    };
+    let (header, hunks) = file_diff.split_at(first_hunk);
+    (header, hunks.split_prefix_inclusive("\n@@ "))
+}
+
+/// Splits a file diff into several smaller file diffs, each holding whole hunks
+/// and staying under `max_tokens` where possible. Every chunk repeats the file
+/// header so it can be summarized on its own.
+pub(crate) fn chunk_file_diff(
+    file_diff: &str,
+    max_tokens: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> Vec<String> {
+    let (header, hunks) = split_hunks(file_diff);
+    let header_tokens = count_tokens(header);
+
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    let mut chunk_tokens = header_tokens;
+    for hunk in hunks {
+        let hunk = hunk.strip_prefix('\n').unwrap_or(hunk);
+        let hunk_tokens = count_tokens(hunk);
+        if !chunk.is_empty() && chunk_tokens + hunk_tokens > max_tokens {
+            chunks.push(format!("{header}{chunk}"));
+            chunk.clear();
+            chunk_tokens = header_tokens;
+        }
+        if !chunk.is_empty() && !chunk.ends_with('\n') {
+            chunk.push('\n');
+        }
+        chunk.push_str(hunk);
+        chunk_tokens += hunk_tokens;
+    }
+    if !chunk.is_empty() || chunks.is_empty() {
+        chunks.push(format!("{header}{chunk}"));
+    }
+    chunks
+}
+
+/// Keeps at most `context_lines` unchanged lines around the changes of each
+/// hunk, splitting hunks whose changes end up apart and recomputing their `@@`
+/// headers. Hunks with headers it can't parse are kept as they are.
+pub(crate) fn trim_context(file_diff: &str, context_lines: usize) -> String {
+    let (header, hunks) = split_hunks(file_diff);
+    let mut trimmed = header.to_string();
+    for hunk in hunks {
+        let hunk = hunk.strip_prefix('\n').unwrap_or(hunk);
+        match trim_hunk_context(hunk, context_lines) {
+            Some(hunk) => trimmed.push_str(&hunk),
+            None => {
+                trimmed.push_str(hunk);
+                if !hunk.ends_with('\n') {
+                    trimmed.push('\n');
+                }
+            }
+        }
+    }
+    trimmed
+}
+
+fn trim_hunk_context(hunk: &str, context_lines: usize) -> Option<String> {
+    let mut lines = hunk.lines();
+    let captures = HUNK_HEADER_REGEX.captures(lines.next()?)?;
+    // the number of the next line of each side; an empty side starts at the line before it
+    let next_line = |start: &str, count: Option<regex::Match>| {
+        let start = start.parse::<usize>().ok()?;
+        Some(start + usize::from(count.is_some_and(|count| count.as_str() == "0")))
+    };
+    let mut old_line = next_line(&captures[1], captures.get(2))?;
+    let mut new_line = next_line(&captures[3], captures.get(4))?;
+    let section = captures.get(5).map_or("", |m| m.as_str());
+    let lines = lines.collect::<Vec<_>>();
+
+    // keep the changed lines, the context lines near them, and the
+    // "\ No newline at end of file" markers of kept lines
+    let changed = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.starts_with('+') || line.starts_with('-'))
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    let mut keep = vec![false; lines.len()];
+    for (idx, line) in lines.iter().enumerate() {
+        keep[idx] = if line.starts_with('\\') {
+            idx > 0 && keep[idx - 1]
+        } else {
+            changed
+                .iter()
+                .any(|&change| change.abs_diff(idx) <= context_lines)
+        };
+    }
+
+    let mut trimmed = String::new();
+    let mut idx = 0;
+    while idx < lines.len() {
+        if !keep[idx] {
+            if !lines[idx].starts_with('\\') {
+                old_line += usize::from(!lines[idx].starts_with('+'));
+                new_line += usize::from(!lines[idx].starts_with('-'));
+            }
+            idx += 1;
+            continue;
+        }
+        let (old_start, new_start) = (old_line, new_line);
+        let mut body = String::new();
+        while idx < lines.len() && keep[idx] {
+            let line = lines[idx];
+            if !line.starts_with('\\') {
+                old_line += usize::from(!line.starts_with('+'));
+                new_line += usize::from(!line.starts_with('-'));
+            }
+            body.push_str(line);
+            body.push('\n');
+            idx += 1;
+        }
+        trimmed.push_str(&format!(
+            "@@ -{} +{} @@{section}\n{body}",
+            hunk_range(old_start, old_line - old_start),
+            hunk_range(new_start, new_line - new_start),
+        ));
+    }
+    Some(trimmed)
+}
+
+/// The `start,count` of one side of a hunk header, as git writes it: the count
+/// is left out when it is 1, and an empty side starts at the line before it.
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start.saturating_sub(1)),
+        1 => start.to_string(),
+        _ => format!("{start},{count}"),
+    }
+}
+
+/// Whether the file diff adds a new file.
+pub(crate) fn is_new_file(file_diff: &str) -> bool {
+    split_hunks(file_diff)
+        .0
+        .lines()
+        .any(|line| line.starts_with("new file mode"))
+}
+
+/// Whether the file diff deletes a file.
+pub(crate) fn is_deleted_file(file_diff: &str) -> bool {
+    split_hunks(file_diff)
+        .0
+        .lines()
+        .any(|line| line.starts_with("deleted file mode"))
+}
+
+/// How a file diff changes the file, given to the per-file prompts as `change_type`.
+#[derive(Debug, Clone, Copy, PartialEq, Display)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum ChangeType {
+    Added,
+    Deleted,
+    Modified,
+    Renamed,
+}
+
+/// Tells added and deleted files, whose diffs hold only `+` or only `-` lines,
+/// and renamed files apart from modified ones by the file diff header.
+pub(crate) fn change_type(file_diff: &str) -> ChangeType {
+    let header = split_hunks(file_diff).0;
+    let has_line = |prefixes: &[&str]| {
+        header
+            .lines()
+            .any(|line| prefixes.iter().any(|prefix| line.starts_with(prefix)))
+    };
+    if has_line(&["new file mode", "--- /dev/null"]) {
+        ChangeType::Added
+    } else if has_line(&["deleted file mode", "+++ /dev/null"]) {
+        ChangeType::Deleted
+    } else if has_line(&["rename from ", "rename to "]) {
+        ChangeType::Renamed
+    } else {
+        ChangeType::Modified
+    }
+}
+
+/// Whether the file diff changes a symbolic link, i.e. has mode `120000`.
+pub(crate) fn is_symlink(file_diff: &str) -> bool {
+    split_hunks(file_diff).0.lines().any(|line| {
+        (line.starts_with("index ")
+            || line.starts_with("new file mode")
+            || line.starts_with("deleted file mode")
+            || line.starts_with("new mode"))
+            && line.ends_with(" 120000")
+    })
+}
+
+/// Whether the file diff changes a binary file, which git shows without content
+/// as `Binary files a/x and b/x differ`, or as a `GIT binary patch`.
+pub(crate) fn is_binary(file_diff: &str) -> bool {
+    split_hunks(file_diff)
+        .0
+        .lines()
+        .any(|line| line.starts_with("Binary files ") || line == "GIT binary patch")
+}
+
+/// The target path of a symbolic link after the change, which git diffs as the
+/// content of the link. None when the link is deleted.
+pub(crate) fn symlink_target(file_diff: &str) -> Option<&str> {
+    let (_, hunks) = split_hunks(file_diff);
+    hunks
+        .iter()
+        .flat_map(|hunk| hunk.lines())
+        .filter_map(|line| line.strip_prefix('+'))
+        .next_back()
+}
+
+/// The commits a submodule points to before and after the change, which git
+/// diffs as a `Subproject commit <sha>` line. None when the file diff is not a
+/// submodule pointer change, i.e. its header doesn't give the gitlink mode
+/// 160000; either commit is None when the submodule is added or removed.
+pub(crate) fn submodule_commits(file_diff: &str) -> Option<(Option<&str>, Option<&str>)> {
+    const SUBPROJECT_COMMIT: &str = "Subproject commit ";
+    let (header, hunks) = split_hunks(file_diff);
+    let is_gitlink = header.lines().any(|line| {
+        line.strip_prefix("index ")
+            .and_then(|index| index.split_whitespace().nth(1))
+            .or_else(|| line.strip_prefix("new file mode "))
+            .or_else(|| line.strip_prefix("deleted file mode "))
+            .is_some_and(|mode| mode.trim() == "160000")
+    });
+    if !is_gitlink {
+        return None;
+    }
+    let lines = hunks
+        .iter()
+        .flat_map(|hunk| hunk.lines())
+        .collect::<Vec<_>>();
+    let commit = |side: char| {
+        lines.iter().find_map(|line| {
+            line.strip_prefix(side)?
+                .strip_prefix(SUBPROJECT_COMMIT)?
+                .split_whitespace()
+                .next()
+        })
+    };
+    match (commit('-'), commit('+')) {
+        (None, None) => None,
+        commits => Some(commits),
+    }
+}
+
+/// A file diff without any substantive change to the file's content.
+#[derive(Debug, PartialEq)]
+pub(crate) enum TrivialChange<'a> {
+    /// Only the file mode changed, e.g. the file was made executable
+    Mode { old: &'a str, new: &'a str },
+    /// Only whitespace changed
+    Whitespace,
+}
+
+/// Detects file diffs changing only the file mode or whitespace. The removed
+/// and added lines of every hunk are compared with all whitespace removed, so
+/// re-indented and re-wrapped lines count as whitespace-only while content
+/// moved between hunks does not. New, deleted, renamed and binary files are
+/// never trivial.
+pub(crate) fn trivial_change(file_diff: &str) -> Option<TrivialChange<'_>> {
+    let (header, hunks) = split_hunks(file_diff);
+    let header_lines = header.lines().collect::<Vec<_>>();
+    let is_structural = |line: &&str| {
+        [
+            "new file mode",
+            "deleted file mode",
+            "rename ",
+            "copy ",
+            "similarity index",
+            "Binary files",
+            "GIT binary patch",
+        ]
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+    };
+    if header_lines.iter().any(is_structural) {
+        return None;
+    }
+    let old_mode = header_lines
+        .iter()
+        .find_map(|line| line.strip_prefix("old mode "));
+    let new_mode = header_lines
+        .iter()
+        .find_map(|line| line.strip_prefix("new mode "));
+    if hunks.is_empty() && old_mode.is_none() && !header.contains("\nindex ") {
+        return None;
+    }
+    let non_whitespace = |content: &str| {
+        content
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+    };
+    for hunk in &hunks {
+        let (mut removed, mut added) = (String::new(), String::new());
+        for line in hunk.lines().filter(|line| !line.starts_with("@@ ")) {
+            if let Some(content) = line.strip_prefix('-') {
+                removed.push_str(&non_whitespace(content));
+            } else if let Some(content) = line.strip_prefix('+') {
+                added.push_str(&non_whitespace(content));
+            }
+        }
+        if removed != added {
+            return None;
+        }
+    }
+    match (old_mode, new_mode) {
+        (Some(old), Some(new)) => Some(TrivialChange::Mode { old, new }),
+        _ => Some(TrivialChange::Whitespace),
+    }
+}
+
+/// Condenses the diff of a newly added file into its header and the added
+/// top-level lines, like imports and top-level declarations. Indented lines
+/// and lines holding only closing brackets are left out.
+pub(crate) fn new_file_outline(file_diff: &str) -> String {
+    let (header, hunks) = split_hunks(file_diff);
+    let mut outline = header.to_string();
+    for line in hunks.iter().flat_map(|hunk| hunk.lines()) {
+        let Some(added) = line.strip_prefix('+') else {
+            continue;
+        };
+        let is_top_level = added.starts_with(|c: char| !c.is_whitespace());
+        let is_closing = added
+            .chars()
+            .all(|c| matches!(c, '}' | ')' | ']' | ';' | ','));
+        if is_top_level && !is_closing {
+            outline.push_str(added);
+            outline.push('\n');
+        }
+    }
+    outline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &str = r#"diff --git a/foo.txt b/foo.txt
+index 257cc56..3bd1f0e 100644
+--- a/foo.txt
++++ b/foo.txt
+@@ -1,2 +1,2 @@
+-foo
++bar
+ baz
+@@ -10,2 +10,2 @@ fn main() {
+-qux
++quux
+ corge
+"#;
+
+    #[test]
+    fn test_split_hunks() {
+        let (header, hunks) = split_hunks(DIFF);
+        assert!(header.starts_with("diff --git a/foo.txt"));
+        assert!(header.ends_with("+++ b/foo.txt\n"));
+        assert_eq!(hunks.len(), 2);
+        assert!(hunks[0].starts_with("@@ -1,2"));
+        assert!(hunks[1].starts_with("\n@@ -10,2"));
+    }
+
+    #[test]
+    fn test_trim_context() {
+        let diff = "diff --git a/f.txt b/f.txt\nindex 1..2 100644\n--- a/f.txt\n+++ b/f.txt\n@@ -1,12 +1,12 @@ fn main() {\n l1\n l2\n l3\n-l4\n+L4\n l5\n l6\n l7\n l8\n l9\n-l10\n+L10\n l11\n l12\n";
+        let header = "diff --git a/f.txt b/f.txt\nindex 1..2 100644\n--- a/f.txt\n+++ b/f.txt\n";
+
+        assert_eq!(trim_context(diff, 3), diff);
+        assert_eq!(
+            trim_context(diff, 1),
+            format!("{header}@@ -3,3 +3,3 @@ fn main() {{\n l3\n-l4\n+L4\n l5\n@@ -9,3 +9,3 @@ fn main() {{\n l9\n-l10\n+L10\n l11\n")
+        );
+        let changes_only = trim_context(diff, 0);
+        assert_eq!(
+            changes_only,
+            format!("{header}@@ -4 +4 @@ fn main() {{\n-l4\n+L4\n@@ -10 +10 @@ fn main() {{\n-l10\n+L10\n")
+        );
+        assert!(changes_only.len() < diff.len());
+        let changed_lines = |diff: &str| {
+            diff.lines()
+                .filter(|line| line.starts_with(['+', '-']))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(changed_lines(&changes_only), changed_lines(diff));
+
+        // an insertion keeps the position of the empty side
+        let insertion =
+            "--- a/f.txt\n+++ b/f.txt\n@@ -5,2 +5,3 @@\n l5\n+x\n l6\n\\ No newline at end of file\n";
+        assert_eq!(
+            trim_context(insertion, 0),
+            "--- a/f.txt\n+++ b/f.txt\n@@ -5,0 +6 @@\n+x\n"
+        );
+        let new_file = "--- /dev/null\n+++ b/f.txt\n@@ -0,0 +1,2 @@\n+a\n+b\n";
+        assert_eq!(trim_context(new_file, 0), new_file);
+    }
+
+    #[test]
+    fn test_symlink() {
+        let diff = "diff --git a/current b/current\nindex 1b2c3d4..5e6f7a8 120000\n--- a/current\n+++ b/current\n@@ -1 +1 @@\n-releases/v1\n\\ No newline at end of file\n+releases/v2\n\\ No newline at end of file\n";
+        assert!(is_symlink(diff));
+        assert_eq!(symlink_target(diff), Some("releases/v2"));
+
+        let deleted = "diff --git a/current b/current\ndeleted file mode 120000\nindex 1b2c3d4..0000000\n--- a/current\n+++ /dev/null\n@@ -1 +0,0 @@\n-releases/v1\n\\ No newline at end of file\n";
+        assert!(is_symlink(deleted));
+        assert_eq!(symlink_target(deleted), None);
+
+        assert!(!is_symlink(DIFF));
+    }
+
+    #[test]
+    fn test_submodule_commits() {
+        let bump = "diff --git a/vendor/lib b/vendor/lib\nindex 1b2c3d4..5e6f7a8 160000\n--- a/vendor/lib\n+++ b/vendor/lib\n@@ -1 +1 @@\n-Subproject commit 1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e\n+Subproject commit 5e6f7a8b9c0d1e2f3a4b5c6d7e8f90a1b2c3d4e5\n";
+        assert_eq!(
+            submodule_commits(bump),
+            Some((
+                Some("1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e"),
+                Some("5e6f7a8b9c0d1e2f3a4b5c6d7e8f90a1b2c3d4e5")
+            ))
+        );
+
+        let added = "diff --git a/vendor/lib b/vendor/lib\nnew file mode 160000\nindex 0000000..5e6f7a8\n--- /dev/null\n+++ b/vendor/lib\n@@ -0,0 +1 @@\n+Subproject commit 5e6f7a8b9c0d1e2f3a4b5c6d7e8f90a1b2c3d4e5\n";
+        assert_eq!(
+            submodule_commits(added),
+            Some((None, Some("5e6f7a8b9c0d1e2f3a4b5c6d7e8f90a1b2c3d4e5")))
+        );
+
+        assert_eq!(submodule_commits(DIFF), None);
+        // a regular file mentioning a submodule commit is not a submodule
+        let notes = "diff --git a/notes.txt b/notes.txt\nindex 1b2c3d4..5e6f7a8 100644\n--- a/notes.txt\n+++ b/notes.txt\n@@ -1 +1 @@\n-Subproject commit 1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e\n+Subproject commit 5e6f7a8b9c0d1e2f3a4b5c6d7e8f90a1b2c3d4e5\n";
+        assert_eq!(submodule_commits(notes), None);
+    }
+
+    #[test]
+    fn test_binary() {
+        let diff = "diff --git a/logo.png b/logo.png\nindex 1b2c3d4..5e6f7a8 100644\nBinary files a/logo.png and b/logo.png differ\n";
+        assert!(is_binary(diff));
+        let patch = "diff --git a/logo.png b/logo.png\nindex 1b2c3d4..5e6f7a8 100644\nGIT binary patch\nliteral 12\nzcmV\n";
+        assert!(is_binary(patch));
+        assert!(!is_binary(DIFF));
+    }
+
+    #[test]
+    fn test_trivial_change() {
+        let mode_only = "diff --git a/run.sh b/run.sh\nold mode 100644\nnew mode 100755\n";
+        assert_eq!(
+            trivial_change(mode_only),
+            Some(TrivialChange::Mode {
+                old: "100644",
+                new: "100755"
+            })
+        );
+
+        let whitespace_only = "diff --git a/foo.rs b/foo.rs\nindex 1..2 100644\n--- a/foo.rs\n+++ b/foo.rs\n@@ -1,3 +1,2 @@\n-fn foo(a: u32,\n-       b: u32) {}  \n+fn foo(a: u32, b: u32) {}\n \n";
+        assert_eq!(
+            trivial_change(whitespace_only),
+            Some(TrivialChange::Whitespace)
+        );
+
+        assert_eq!(trivial_change(DIFF), None);
+
+        // content moved between hunks is a real change
+        let moved = "diff --git a/foo.txt b/foo.txt\nindex 1..2 100644\n--- a/foo.txt\n+++ b/foo.txt\n@@ -1,2 +1 @@\n-foo\n bar\n@@ -10,1 +9,2 @@\n baz\n+foo\n";
+        assert_eq!(trivial_change(moved), None);
+
+        let new_empty_file =
+            "diff --git a/empty b/empty\nnew file mode 100644\nindex 0000000..e69de29\n";
+        assert_eq!(trivial_change(new_empty_file), None);
+    }
+
+    #[test]
+    fn test_change_type() {
+        let added = "diff --git a/new.rs b/new.rs\nnew file mode 100644\nindex 0000000..3bd1f0e\n--- /dev/null\n+++ b/new.rs\n@@ -0,0 +1 @@\n+fn new() {}\n";
+        assert_eq!(change_type(added), ChangeType::Added);
+
+        let deleted = "diff --git a/parser.rs b/parser.rs\ndeleted file mode 100644\nindex 3bd1f0e..0000000\n--- a/parser.rs\n+++ /dev/null\n@@ -1 +0,0 @@\n-fn parse() {}\n";
+        assert_eq!(change_type(deleted), ChangeType::Deleted);
+        // without the mode line, e.g. in a hand-edited patch
+        let deleted = "diff --git a/parser.rs b/parser.rs\n--- a/parser.rs\n+++ /dev/null\n@@ -1 +0,0 @@\n-fn parse() {}\n";
+        assert_eq!(change_type(deleted), ChangeType::Deleted);
+
+        let renamed = "diff --git a/old.rs b/new.rs\nsimilarity index 90%\nrename from old.rs\nrename to new.rs\nindex 1..2 100644\n--- a/old.rs\n+++ b/new.rs\n@@ -1 +1 @@\n-fn old() {}\n+fn new() {}\n";
+        assert_eq!(change_type(renamed), ChangeType::Renamed);
+
+        assert_eq!(change_type(DIFF), ChangeType::Modified);
+        // hunk lines never count as header lines
+        let modified = "diff --git a/a.txt b/a.txt\nindex 1..2 100644\n--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n--- /dev/null\n+new file mode\n";
+        assert_eq!(change_type(modified), ChangeType::Modified);
+        assert_eq!(ChangeType::Deleted.to_string(), "deleted");
+    }
+
+    #[test]
+    fn test_split_hunks_without_hunks() {
+        let diff = "diff --git a/img.png b/img.png\nBinary files a/img.png and b/img.png differ\n";
+        assert_eq!(split_hunks(diff), (diff, vec![]));
+    }
+
+    #[test]
+    fn test_new_file_outline() {
+        let diff = r#"diff --git a/src/lib.rs b/src/lib.rs
+new file mode 100644
+index 0000000..3bd1f0e
+--- /dev/null
++++ b/src/lib.rs
+@@ -0,0 +1,7 @@
++use std::fmt;
++
++pub fn render() -> String {
++    let body = fmt::format(format_args!("x"));
++    body
++}
++pub struct Widget;
+"#;
+        assert!(is_new_file(diff));
+        assert!(!is_new_file(DIFF));
+        assert_eq!(
+            new_file_outline(diff),
+            "diff --git a/src/lib.rs b/src/lib.rs\nnew file mode 100644\nindex 0000000..3bd1f0e\n--- /dev/null\n+++ b/src/lib.rs\nuse std::fmt;\npub fn render() -> String {\npub struct Widget;\n"
+        );
+    }
+
+    #[test]
+    fn test_chunk_file_diff() {
+        let chunks = chunk_file_diff(DIFF, 10, |text| text.lines().count());
+        assert_eq!(chunks.len(), 2);
+        for chunk in &chunks {
+            assert!(chunk.starts_with("diff --git a/foo.txt"));
+        }
+        assert!(chunks[0].contains("+bar") && !chunks[0].contains("+quux"));
+        assert!(chunks[1].contains("+quux") && !chunks[1].contains("+bar"));
+
+        let chunks = chunk_file_diff(DIFF, 1000, |text| text.lines().count());
+        assert_eq!(chunks, vec![DIFF.to_string()]);
+    }
+}
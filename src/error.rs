@@ -0,0 +1,196 @@
+use std::{error::Error, fmt};
+
+use async_openai::error::OpenAIError;
+
+pub type Result<T, E = SummarizeError> = std::result::Result<T, E>;
+
+/// The ways summarizing changes can fail, for callers matching on the failure
+/// instead of inspecting error messages.
+#[derive(Debug)]
+pub enum SummarizeError {
+    /// The provider rejected the API key
+    Auth(String),
+    /// No API key is configured for the provider
+    MissingApiKey(String),
+    /// The provider's rate limit or quota was reached
+    RateLimit(String),
+    /// The run reached `limits.max_total_tokens`
+    BudgetExceeded(String),
+    /// The prompt doesn't fit in the model's context window
+    ContextLengthExceeded(String),
+    /// The provider could not be reached
+    Network(String),
+    /// A prompt template could not be loaded or rendered
+    Template(String),
+    /// The settings are invalid
+    Config(String),
+    /// The provider failed otherwise, e.g. with an error response or without a completion
+    Provider(String),
+    /// The run was cancelled, e.g. with Ctrl-C, before the message was generated
+    Cancelled,
+    /// Any other failure
+    Other(anyhow::Error),
+}
+
+impl SummarizeError {
+    /// Classifies an error response of an OpenAI-compatible API by its HTTP
+    /// status, when known, and the `code` and `type` of its error object.
+    pub(crate) fn from_api_error(
+        status: Option<u16>,
+        message: &str,
+        error_type: Option<&str>,
+        code: Option<&str>,
+    ) -> Self {
+        let message = message.to_string();
+        let is = |value: &str| code == Some(value) || error_type == Some(value);
+        if matches!(status, Some(401 | 403)) || is("invalid_api_key") || is("authentication_error")
+        {
+            Self::Auth(message)
+        } else if is("context_length_exceeded") || message.contains("maximum context length") {
+            Self::ContextLengthExceeded(message)
+        } else if status == Some(429)
+            || is("rate_limit_exceeded")
+            || is("insufficient_quota")
+            || is("requests")
+            || is("tokens")
+        {
+            Self::RateLimit(message)
+        } else {
+            Self::Provider(message)
+        }
+    }
+
+    /// Classifies the JSON error body of an OpenAI-compatible API response.
+    pub(crate) fn from_response(status: u16, body: &str) -> Self {
+        let json = serde_json::from_str::<serde_json::Value>(body).unwrap_or_default();
+        let error = &json["error"];
+        let message = error["message"]
+            .as_str()
+            .map_or_else(|| format!("HTTP {status}: {body}"), str::to_string);
+        Self::from_api_error(
+            Some(status),
+            &message,
+            error["type"].as_str(),
+            error["code"].as_str(),
+        )
+    }
+}
+
+impl fmt::Display for SummarizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auth(message) => write!(f, "Authentication failed: {message}"),
+            Self::RateLimit(message) => write!(f, "Rate limit reached: {message}"),
+            Self::BudgetExceeded(message) => write!(f, "Token budget exceeded: {message}"),
+            Self::ContextLengthExceeded(message) => write!(f, "{message}"),
+            Self::Network(message) => write!(f, "Network error: {message}"),
+            Self::MissingApiKey(message)
+            | Self::Template(message)
+            | Self::Config(message)
+            | Self::Provider(message) => {
+                write!(f, "{message}")
+            }
+            Self::Cancelled => write!(f, "Cancelled, the commit message was left untouched"),
+            Self::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Error for SummarizeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Whether creating a client failed only for want of an API key.
+pub(crate) fn is_missing_api_key(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<SummarizeError>(),
+        Some(SummarizeError::MissingApiKey(_))
+    )
+}
+
+impl From<anyhow::Error> for SummarizeError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<SummarizeError>() {
+            Ok(e) => e,
+            Err(e) => Self::Other(e),
+        }
+    }
+}
+
+impl From<OpenAIError> for SummarizeError {
+    fn from(e: OpenAIError) -> Self {
+        match e {
+            OpenAIError::ApiError(e) => Self::from_api_error(
+                None,
+                &e.message,
+                e.r#type.as_deref(),
+                e.code.as_ref().and_then(|code| code.as_str()),
+            ),
+            OpenAIError::Reqwest(e) => e.into(),
+            e => Self::Provider(e.to_string()),
+        }
+    }
+}
+
+impl From<reqwest::Error> for SummarizeError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(status) => Self::from_api_error(Some(status.as_u16()), &e.to_string(), None, None),
+            None if e.is_decode() => Self::Provider(e.to_string()),
+            None => Self::Network(e.to_string()),
+        }
+    }
+}
+
+impl From<tera::Error> for SummarizeError {
+    fn from(e: tera::Error) -> Self {
+        Self::Template(tera_error_message(&e))
+    }
+}
+
+/// The message of a tera error including its causes, as tera's own message
+/// only names the template.
+pub(crate) fn tera_error_message(error: &tera::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+    message
+}
+
+impl From<tokio::task::JoinError> for SummarizeError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Self::Other(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_response() {
+        assert!(matches!(
+            SummarizeError::from_response(
+                401,
+                r#"{"error":{"message":"Incorrect API key provided","type":"invalid_request_error","param":null,"code":"invalid_api_key"}}"#
+            ),
+            SummarizeError::Auth(message) if message == "Incorrect API key provided"
+        ));
+        assert!(matches!(
+            SummarizeError::from_response(429, "Too Many Requests"),
+            SummarizeError::RateLimit(message) if message == "HTTP 429: Too Many Requests"
+        ));
+        assert!(matches!(
+            SummarizeError::from_response(500, "{}"),
+            SummarizeError::Provider(_)
+        ));
+    }
+}
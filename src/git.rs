@@ -1,7 +1,7 @@
 use std::{
     fs::{self},
-    path::PathBuf,
-    process::Command,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
 };
 
 #[cfg(unix)]
@@ -9,28 +9,214 @@ use std::{fs::Permissions, os::unix::prelude::PermissionsExt};
 
 use crate::cmd;
 use anyhow::{bail, Result};
+use regex::Regex;
 
-pub(crate) fn get_diffs() -> Result<String> {
-    let output = cmd::run_command(
+/// The options of every `git diff` whose changes are summarized.
+static DIFF_OPTIONS: &[&str] = &[
+    "--ignore-all-space",
+    "--diff-algorithm=minimal",
+    "--function-context",
+    "--no-ext-diff",
+    "--no-color",
+];
+
+/// Returns the staged changes of the current repository, limited to the
+/// paths matching `pathspecs` unless they are empty.
+pub(crate) fn get_diffs(pathspecs: &[String]) -> Result<String> {
+    get_diffs_in(Path::new("."), pathspecs)
+}
+
+/// Returns the staged changes of the repository at `repo`.
+pub(crate) fn get_diffs_in(repo: &Path, pathspecs: &[String]) -> Result<String> {
+    diff_in(repo, &["--staged"], pathspecs)
+}
+
+/// Returns the staged and unstaged changes of the current repository, as `git diff HEAD` shows them.
+pub(crate) fn get_working_tree_diffs(pathspecs: &[String]) -> Result<String> {
+    get_working_tree_diffs_in(Path::new("."), pathspecs)
+}
+
+/// Returns the staged and unstaged changes of the repository at `repo`
+/// against HEAD, or against the empty tree before the first commit.
+/// Untracked files are not included.
+pub(crate) fn get_working_tree_diffs_in(repo: &Path, pathspecs: &[String]) -> Result<String> {
+    let has_head = Command::new("git")
+        .args(["rev-parse", "--verify", "--quiet", "HEAD"])
+        .current_dir(repo)
+        .output()?
+        .status
+        .success();
+    let base = if has_head {
+        "HEAD".to_string()
+    } else {
+        let output = Command::new("git")
+            .args(["hash-object", "-t", "tree", "--stdin"])
+            .current_dir(repo)
+            .stdin(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr));
+        }
+        String::from_utf8(output.stdout)
+            .expect("Invalid UTF-8")
+            .trim()
+            .to_string()
+    };
+    diff_in(repo, &[&base], pathspecs)
+}
+
+/// Returns the combined changes of a revision range such as `main..feature`:
+/// the changes of its commits, diffed from the merge base like
+/// `git diff main...feature`, so that commits made on `main` since the branch
+/// diverged don't show up as reverted.
+pub(crate) fn get_range_diffs(range: &str, pathspecs: &[String]) -> Result<String> {
+    get_range_diffs_in(Path::new("."), range, pathspecs)
+}
+
+/// Returns the combined changes of a revision range of the repository at `repo`.
+pub(crate) fn get_range_diffs_in(repo: &Path, range: &str, pathspecs: &[String]) -> Result<String> {
+    let range = if range.contains("...") {
+        range.to_string()
+    } else {
+        range.replacen("..", "...", 1)
+    };
+    diff_in(repo, &[&range], pathspecs)
+}
+
+/// Returns the subjects of the commits in a revision range of the current repository.
+pub(crate) fn get_range_commit_subjects(range: &str) -> Result<Vec<String>> {
+    get_range_commit_subjects_in(Path::new("."), range)
+}
+
+/// Returns the subjects of the commits in a revision range of the repository
+/// at `repo`, oldest first and without merges.
+pub(crate) fn get_range_commit_subjects_in(repo: &Path, range: &str) -> Result<Vec<String>> {
+    let log = cmd::run_command_in(
+        repo,
         "git",
         &[
-            "diff",
-            "--staged",
-            "--ignore-all-space",
-            "--diff-algorithm=minimal",
-            "--function-context",
-            "--no-ext-diff",
-            "--no-color",
+            "log",
+            "--format=%s",
+            "--no-merges",
+            "--reverse",
+            range,
+            "--",
         ],
     )?;
+    Ok(log
+        .lines()
+        .filter(|subject| !subject.trim().is_empty())
+        .map(str::to_string)
+        .collect())
+}
 
-    Ok(output)
+fn diff_in(repo: &Path, args: &[&str], pathspecs: &[String]) -> Result<String> {
+    let mut args = ["diff"]
+        .iter()
+        .chain(args)
+        .chain(DIFF_OPTIONS)
+        .copied()
+        .collect::<Vec<_>>();
+    if !pathspecs.is_empty() {
+        args.push("--");
+        args.extend(pathspecs.iter().map(String::as_str));
+    }
+    cmd::run_command_in(repo, "git", &args)
+}
+
+/// Returns the diff a commit introduced, as `git show` shows it, limited to
+/// the paths matching `pathspecs` unless they are empty.
+pub(crate) fn get_commit_diff(commit: &str, pathspecs: &[String]) -> Result<String> {
+    let mut args = ["show", "--format="]
+        .iter()
+        .chain(DIFF_OPTIONS)
+        .copied()
+        .collect::<Vec<_>>();
+    args.push(commit);
+    if !pathspecs.is_empty() {
+        args.push("--");
+        args.extend(pathspecs.iter().map(String::as_str));
+    }
+    cmd::run_command("git", &args)
+}
+
+/// Returns the name of the checked out branch, or None when HEAD is detached.
+pub(crate) fn get_current_branch() -> Result<Option<String>> {
+    let output = Command::new("git")
+        .args(["symbolic-ref", "--short", "--quiet", "HEAD"])
+        .output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let branch = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    Ok(Some(branch.trim().to_string()))
+}
+
+/// Returns the subjects of the latest `count` commits of the current repository.
+pub(crate) fn get_recent_commit_subjects(count: usize) -> Result<Vec<String>> {
+    get_recent_commit_subjects_in(Path::new("."), count)
+}
+
+/// Returns the subjects of the latest `count` commits of the repository at
+/// `repo`, newest first and without merges. Fails outside a repository and before the first commit.
+pub(crate) fn get_recent_commit_subjects_in(repo: &Path, count: usize) -> Result<Vec<String>> {
+    let count = format!("--max-count={count}");
+    let log = cmd::run_command_in(repo, "git", &["log", &count, "--format=%s", "--no-merges"])?;
+    Ok(log
+        .lines()
+        .filter(|subject| !subject.trim().is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Returns the distinct ticket ids matching `ticket_regex` in the branch name, in order.
+pub(crate) fn extract_tickets(branch: &str, ticket_regex: &Regex) -> Vec<String> {
+    let mut tickets: Vec<String> = vec![];
+    for ticket in ticket_regex.find_iter(branch) {
+        if !tickets.iter().any(|t| t == ticket.as_str()) {
+            tickets.push(ticket.as_str().to_string());
+        }
+    }
+    tickets
+}
+
+/// Returns the path of `path` inside the git directory of the current repository,
+/// e.g. `.git/gptcommit/last_message.json`, which may not exist yet.
+pub(crate) fn get_git_path(path: &str) -> Result<PathBuf> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--git-path", path])
+        .output()?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr));
+    }
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    Ok(PathBuf::from(stdout.trim()))
+}
+
+/// Attaches the contents of `note_file` to HEAD of the repository at `repo` as
+/// a note under `refs/notes/<notes_ref>`, replacing any note already there.
+pub(crate) fn add_note_in(repo: &Path, notes_ref: &str, note_file: &Path) -> Result<()> {
+    let note_file = note_file.to_string_lossy();
+    cmd::run_command_in(
+        repo,
+        "git",
+        &[
+            "notes", "--ref", notes_ref, "add", "--force", "--file", &note_file, "HEAD",
+        ],
+    )?;
+    Ok(())
 }
 
 /// Given current working directory, return path to .git/hooks
 pub(crate) fn get_hooks_path() -> Result<PathBuf> {
+    get_hooks_path_in(Path::new("."))
+}
+
+/// Returns the hooks directory of the repository at `repo`, honoring `core.hooksPath`.
+pub(crate) fn get_hooks_path_in(repo: &Path) -> Result<PathBuf> {
     let command_output = Command::new("git")
         .args(["rev-parse", "--show-toplevel", "--git-path", "hooks"])
+        .current_dir(repo)
         .output()?;
     info!("Repo path from git: {:?}", command_output);
 
@@ -40,8 +226,8 @@ pub(crate) fn get_hooks_path() -> Result<PathBuf> {
     }
 
     let stdout = String::from_utf8(command_output.stdout).expect("Invalid UTF-8");
-    let rel_hooks_path = stdout.lines().last().unwrap().to_string();
-    info!("Creating dir at {}", rel_hooks_path);
+    let rel_hooks_path = repo.join(stdout.lines().last().unwrap());
+    info!("Creating dir at {}", rel_hooks_path.display());
     // create dirs first otherwise canonicalize will fail
     fs::create_dir_all(&rel_hooks_path)?;
     #[cfg(unix)]
@@ -50,3 +236,112 @@ pub(crate) fn get_hooks_path() -> Result<PathBuf> {
     let hooks_path = std::fs::canonicalize(rel_hooks_path)?;
     Ok(hooks_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::install::tests::temp_repo;
+
+    fn git(repo: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(repo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_working_tree_diffs_include_unstaged_changes() {
+        let repo = temp_repo("working-tree");
+        fs::write(repo.join("staged.txt"), "one\n").unwrap();
+        fs::write(repo.join("unstaged.txt"), "one\n").unwrap();
+        git(&repo, &["add", "."]);
+        git(&repo, &["commit", "--quiet", "-m", "Initial commit"]);
+        fs::write(repo.join("staged.txt"), "two\n").unwrap();
+        git(&repo, &["add", "staged.txt"]);
+        fs::write(repo.join("unstaged.txt"), "two\n").unwrap();
+
+        let staged = get_diffs_in(&repo, &[]).unwrap();
+        assert!(staged.contains("diff --git a/staged.txt b/staged.txt"));
+        assert!(!staged.contains("unstaged.txt"));
+
+        let working_tree = get_working_tree_diffs_in(&repo, &[]).unwrap();
+        assert!(working_tree.contains("diff --git a/staged.txt b/staged.txt"));
+        assert!(working_tree.contains("diff --git a/unstaged.txt b/unstaged.txt"));
+        assert!(working_tree.contains("-one\n+two\n"));
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_working_tree_diffs_before_the_first_commit() {
+        let repo = temp_repo("working-tree-unborn");
+        fs::write(repo.join("new.txt"), "one\n").unwrap();
+        git(&repo, &["add", "new.txt"]);
+        fs::write(repo.join("new.txt"), "two\n").unwrap();
+
+        let working_tree = get_working_tree_diffs_in(&repo, &[]).unwrap();
+        assert!(working_tree.contains("new file mode 100644"));
+        assert!(working_tree.contains("+two\n"));
+        assert!(!working_tree.contains("one"));
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_pathspecs_limit_the_diff() {
+        let repo = temp_repo("pathspecs");
+        for package in ["web", "api"] {
+            fs::create_dir_all(repo.join("packages").join(package)).unwrap();
+            fs::write(repo.join("packages").join(package).join("main.rs"), "one\n").unwrap();
+        }
+        fs::write(repo.join("README.md"), "one\n").unwrap();
+        git(&repo, &["add", "."]);
+
+        let diff = get_diffs_in(&repo, &["packages/web".to_string()]).unwrap();
+        assert!(diff.contains("diff --git a/packages/web/main.rs b/packages/web/main.rs"));
+        assert!(!diff.contains("packages/api"));
+        assert!(!diff.contains("README.md"));
+
+        let pathspecs = ["packages/api".to_string(), "*.md".to_string()];
+        let diff = get_working_tree_diffs_in(&repo, &pathspecs).unwrap();
+        assert!(!diff.contains("packages/web"));
+        assert!(diff.contains("diff --git a/packages/api/main.rs b/packages/api/main.rs"));
+        assert!(diff.contains("diff --git a/README.md b/README.md"));
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_recent_commit_subjects() {
+        let repo = temp_repo("recent-commits");
+        assert!(get_recent_commit_subjects_in(&repo, 2).is_err());
+        for subject in ["Add the parser", "Fix the lexer", "Document the grammar"] {
+            git(
+                &repo,
+                &["commit", "--quiet", "--allow-empty", "-m", subject],
+            );
+        }
+
+        assert_eq!(
+            get_recent_commit_subjects_in(&repo, 2).unwrap(),
+            vec!["Document the grammar", "Fix the lexer"]
+        );
+        assert_eq!(get_recent_commit_subjects_in(&repo, 10).unwrap().len(), 3);
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_extract_tickets() {
+        let ticket_regex = Regex::new(r"[A-Z]+-\d+").unwrap();
+        assert_eq!(
+            extract_tickets("feature/PROJ-123-add-widget", &ticket_regex),
+            vec!["PROJ-123"]
+        );
+        assert_eq!(
+            extract_tickets("fix/PROJ-1-and-OPS-42-PROJ-1", &ticket_regex),
+            vec!["PROJ-1", "OPS-42"]
+        );
+        assert!(extract_tickets("main", &ticket_regex).is_empty());
+        assert!(extract_tickets("feature/proj-123", &ticket_regex).is_empty());
+    }
+}
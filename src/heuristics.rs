@@ -0,0 +1,440 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use whatlang::Lang;
+
+use crate::{
+    diff::is_new_file,
+    settings::Language,
+    util::{self, matches_any_glob},
+};
+
+/// Documentation files, the paths of the `docs` rule of [`PATH_RULES`].
+static DOCS_PATTERNS: &[&str] = &[
+    "*.md",
+    "*.mdx",
+    "*.rst",
+    "*.adoc",
+    "docs/**",
+    "doc/**",
+    "LICENSE*",
+    "CHANGELOG*",
+    "AUTHORS*",
+];
+
+/// Path patterns that, when every changed file matches them, determine the commit type.
+/// Rules are tried in order; the first rule matching all files wins. CI paths are
+/// configurable and checked separately by [`is_ci_only`].
+static PATH_RULES: &[(&str, &[&str])] = &[
+    ("docs", DOCS_PATTERNS),
+    (
+        "build",
+        &[
+            "Cargo.toml",
+            "build.rs",
+            "package.json",
+            "Makefile",
+            "Justfile",
+            "CMakeLists.txt",
+            "*.gradle",
+            "pom.xml",
+            "Dockerfile",
+        ],
+    ),
+];
+
+/// Whether every changed file, and at least one, matches the CI path patterns,
+/// making the commit a `ci` commit.
+pub(crate) fn is_ci_only(file_diffs: &[&str], ci_path_patterns: &[String]) -> bool {
+    let mut file_names = file_diffs
+        .iter()
+        .filter_map(|file_diff| util::get_file_name_from_diff(file_diff))
+        .peekable();
+    file_names.peek().is_some()
+        && file_names.all(|file_name| matches_any_glob(file_name, ci_path_patterns))
+}
+
+/// The number of added and removed lines of a file diff, the size of its change.
+pub(crate) fn changed_lines(file_diff: &str) -> usize {
+    let (added, removed) = line_counts(file_diff);
+    added + removed
+}
+
+/// The numbers of added and of removed lines of a file diff, as `git diff --stat` counts them.
+pub(crate) fn line_counts(file_diff: &str) -> (usize, usize) {
+    let lines = file_diff
+        .lines()
+        .skip_while(|line| !line.starts_with("@@"))
+        .collect::<Vec<_>>();
+    let added = lines.iter().filter(|line| line.starts_with('+')).count();
+    let removed = lines.iter().filter(|line| line.starts_with('-')).count();
+    (added, removed)
+}
+
+/// The size of a commit, as `git diff --shortstat` counts it, for the
+/// `files_changed`, `insertions` and `deletions` variables of the prompts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct DiffStats {
+    pub(crate) files_changed: usize,
+    pub(crate) insertions: usize,
+    pub(crate) deletions: usize,
+}
+
+impl DiffStats {
+    pub(crate) fn of(file_diffs: &[&str]) -> Self {
+        let mut stats = Self::default();
+        for file_diff in file_diffs {
+            if util::get_file_name_from_diff(file_diff).is_none() {
+                continue;
+            }
+            let (added, removed) = line_counts(file_diff);
+            stats.files_changed += 1;
+            stats.insertions += added;
+            stats.deletions += removed;
+        }
+        stats
+    }
+}
+
+/// Whether documentation files account for at least `ratio` of the changed lines,
+/// making a commit that also touches code lean toward `docs`. A ratio of 0 disables this.
+pub(crate) fn is_docs_dominant(file_diffs: &[&str], ratio: f64) -> bool {
+    if ratio <= 0.0 {
+        return false;
+    }
+    let patterns = DOCS_PATTERNS
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>();
+    let (mut docs_lines, mut total_lines) = (0, 0);
+    for file_diff in file_diffs {
+        let Some(file_name) = util::get_file_name_from_diff(file_diff) else {
+            continue;
+        };
+        let lines = changed_lines(file_diff);
+        total_lines += lines;
+        if matches_any_glob(file_name, &patterns) {
+            docs_lines += lines;
+        }
+    }
+    total_lines > 0 && docs_lines as f64 >= ratio * total_lines as f64
+}
+
+/// Infers the conventional commit type from the changed file paths and diffs:
+/// - every file matches one rule of [`PATH_RULES`], e.g. only `*.md` → `docs`
+/// - only test files changed → `test`
+/// - only config files changed, e.g. `*.yaml` or dotfiles → `chore`
+/// - only newly added, non-test files → `feat`
+///
+/// Returns None when the changes are inconclusive.
+pub(crate) fn classify_commit_type(
+    file_diffs: &[&str],
+    test_file_patterns: &[String],
+    config_file_patterns: &[String],
+) -> Option<&'static str> {
+    let files = file_diffs
+        .iter()
+        .filter_map(|file_diff| Some((util::get_file_name_from_diff(file_diff)?, *file_diff)))
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        return None;
+    }
+
+    for (commit_type, patterns) in PATH_RULES {
+        let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        if files
+            .iter()
+            .all(|(file_name, _)| matches_any_glob(file_name, &patterns))
+        {
+            return Some(commit_type);
+        }
+    }
+
+    let is_test = |file_name: &str| matches_any_glob(file_name, test_file_patterns);
+    if files.iter().all(|(file_name, _)| is_test(file_name)) {
+        return Some("test");
+    }
+    if files
+        .iter()
+        .all(|(file_name, _)| matches_any_glob(file_name, config_file_patterns))
+    {
+        return Some("chore");
+    }
+    if files
+        .iter()
+        .all(|(file_name, file_diff)| !is_test(file_name) && is_new_file(file_diff))
+    {
+        return Some("feat");
+    }
+
+    None
+}
+
+/// Directories too generic to serve as a scope, e.g. `src` in `src/parser/`.
+static GENERIC_DIRS: &[&str] = &["src", "lib", "app", "pkg", "crates", "packages", "internal"];
+
+/// Infers the conventional commit scope from the changed file paths: the last
+/// non-generic directory all files share, or the file stem for a single file
+/// in a generic directory. Returns None when the files have nothing specific in common.
+pub(crate) fn detect_scope(file_names: &[&str]) -> Option<String> {
+    let (first, rest) = file_names.split_first()?;
+    let mut common: Vec<&str> = first.split('/').collect();
+    let file_name = common.pop()?;
+    for file_name in rest {
+        let dirs: Vec<&str> = file_name.split('/').collect();
+        let shared = common
+            .iter()
+            .zip(&dirs[..dirs.len() - 1])
+            .take_while(|(a, b)| a == b)
+            .count();
+        common.truncate(shared);
+    }
+    if let Some(dir) = common.iter().rev().find(|dir| !GENERIC_DIRS.contains(dir)) {
+        return Some(dir.to_string());
+    }
+    if rest.is_empty() && !common.is_empty() {
+        let stem = file_name.split('.').next().unwrap_or(file_name);
+        return (!stem.is_empty()).then(|| stem.to_string());
+    }
+    None
+}
+
+lazy_static! {
+    /// The text of a comment, from its marker to the end of the line. The marker
+    /// stands apart, so `#[derive]`, `#include` or `i--` are not comments.
+    static ref COMMENT_REGEX: Regex =
+        Regex::new(r"(?:^|\s)(?://+|/\*+|<!--|#+|--|\*)(?:\s+(.*)|$)").unwrap();
+    /// Comments that are no prose of the author: commented-out code and license headers.
+    static ref NON_PROSE_COMMENT_REGEX: Regex = Regex::new(
+        r"(?i)[;{}]|=|\(\)|^(copyright|spdx-license-identifier|licensed under|all rights reserved)|\(c\)\s*\d{4}"
+    )
+    .unwrap();
+    /// The content of a double-quoted string literal.
+    static ref STRING_REGEX: Regex = Regex::new(r#""((?:[^"\\]|\\.)*)""#).unwrap();
+}
+
+/// Detects the natural language of the comments and string literals added by
+/// the diffs, and of the prose added to documentation files. Commented-out code
+/// and license headers are left out, as they say nothing of the language the
+/// authors write in. Returns None when
+/// the detection isn't reliable or finds a language without a translation name.
+pub(crate) fn detect_language(file_diffs: &[&str]) -> Option<Language> {
+    let docs_patterns = DOCS_PATTERNS
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>();
+    let mut text = String::new();
+    for file_diff in file_diffs {
+        let is_docs = util::get_file_name_from_diff(file_diff)
+            .is_some_and(|file_name| matches_any_glob(file_name, &docs_patterns));
+        let added_lines = file_diff
+            .lines()
+            .skip_while(|line| !line.starts_with("@@"))
+            .filter_map(|line| line.strip_prefix('+'));
+        for line in added_lines {
+            if is_docs {
+                text.push_str(line);
+                text.push('\n');
+                continue;
+            }
+            let comment = COMMENT_REGEX
+                .captures(line)
+                .and_then(|c| c.get(1))
+                .map(|c| c.as_str().to_string())
+                .filter(|comment| !NON_PROSE_COMMENT_REGEX.is_match(comment));
+            let strings = STRING_REGEX
+                .captures_iter(line)
+                .map(|c| c[1].to_string())
+                .collect::<Vec<_>>();
+            for sample in comment.into_iter().chain(strings) {
+                text.push_str(&sample);
+                text.push('\n');
+            }
+        }
+    }
+
+    let info = whatlang::detect(&text).filter(|info| info.is_reliable())?;
+    let tag = match info.lang() {
+        Lang::Eng => "en",
+        Lang::Jpn => "ja",
+        Lang::Cmn => "zh-cn",
+        Lang::Kor => "ko",
+        Lang::Deu => "de",
+        Lang::Fra => "fr",
+        Lang::Spa => "es",
+        Lang::Ita => "it",
+        Lang::Nld => "nl",
+        Lang::Pol => "pl",
+        Lang::Por => "pt",
+        Lang::Rus => "ru",
+        Lang::Ukr => "uk",
+        Lang::Swe => "sv",
+        Lang::Tur => "tr",
+        Lang::Ces => "cs",
+        Lang::Ara => "ar",
+        Lang::Hin => "hi",
+        Lang::Vie => "vi",
+        _ => return None,
+    };
+    Language::from_str(tag).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(file_name: &str) -> String {
+        format!("diff --git a/{file_name} b/{file_name}\nindex 1..2 100644\n--- a/{file_name}\n+++ b/{file_name}\n@@ -1 +1 @@\n-a\n+b\n")
+    }
+
+    fn new_file_diff(file_name: &str) -> String {
+        format!("diff --git a/{file_name} b/{file_name}\nnew file mode 100644\nindex 0000000..2\n--- /dev/null\n+++ b/{file_name}\n@@ -0,0 +1 @@\n+b\n")
+    }
+
+    fn test_patterns() -> Vec<String> {
+        vec!["tests/**".to_string(), "*_test.*".to_string()]
+    }
+
+    fn config_patterns() -> Vec<String> {
+        vec!["*.yaml".to_string(), ".*".to_string()]
+    }
+
+    #[test]
+    fn test_docs_only() {
+        let diffs = [diff("README.md"), diff("docs/usage.rst")];
+        let diffs = diffs.iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(
+            classify_commit_type(&diffs, &test_patterns(), &config_patterns()),
+            Some("docs")
+        );
+    }
+
+    #[test]
+    fn test_test_only() {
+        let diffs = [diff("tests/cli.rs"), diff("src/parser_test.go")];
+        let diffs = diffs.iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(
+            classify_commit_type(&diffs, &test_patterns(), &config_patterns()),
+            Some("test")
+        );
+    }
+
+    #[test]
+    fn test_config_only() {
+        let diffs = [diff("config/app.yaml"), diff(".editorconfig")];
+        let diffs = diffs.iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(
+            classify_commit_type(&diffs, &test_patterns(), &config_patterns()),
+            Some("chore")
+        );
+    }
+
+    #[test]
+    fn test_ci_only() {
+        let ci_patterns = vec![
+            ".github/workflows/**".to_string(),
+            ".gitlab-ci.yml".to_string(),
+        ];
+        let diffs = [diff(".github/workflows/ci.yaml"), diff(".gitlab-ci.yml")];
+        let diffs = diffs.iter().map(String::as_str).collect::<Vec<_>>();
+        assert!(is_ci_only(&diffs, &ci_patterns));
+
+        let diffs = [diff(".github/workflows/ci.yaml"), diff("src/main.rs")];
+        let diffs = diffs.iter().map(String::as_str).collect::<Vec<_>>();
+        assert!(!is_ci_only(&diffs, &ci_patterns));
+        assert!(!is_ci_only(&[], &ci_patterns));
+    }
+
+    #[test]
+    fn test_new_feature_files() {
+        let diffs = [new_file_diff("src/widget.rs")];
+        let diffs = diffs.iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(
+            classify_commit_type(&diffs, &test_patterns(), &config_patterns()),
+            Some("feat")
+        );
+    }
+
+    #[test]
+    fn test_docs_dominant() {
+        let readme = format!("{}+c\n+d\n+e\n", diff("README.md"));
+        assert_eq!(changed_lines(&readme), 5);
+
+        let diffs = [readme.as_str(), &diff("src/main.rs")];
+        assert!(is_docs_dominant(&diffs, 0.7));
+        assert!(!is_docs_dominant(&diffs, 0.9));
+        assert!(!is_docs_dominant(&diffs, 0.0));
+        assert_eq!(
+            classify_commit_type(&diffs, &test_patterns(), &config_patterns()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_detect_scope() {
+        assert_eq!(
+            detect_scope(&["src/llms/openai.rs", "src/llms/mock.rs"]),
+            Some("llms".to_string())
+        );
+        assert_eq!(detect_scope(&["src/git.rs"]), Some("git".to_string()));
+        assert_eq!(
+            detect_scope(&["packages/web/src/app.ts", "packages/web/README.md"]),
+            Some("web".to_string())
+        );
+        assert_eq!(detect_scope(&["src/git.rs", "src/diff.rs"]), None);
+        assert_eq!(detect_scope(&["README.md", "src/main.rs"]), None);
+        assert_eq!(detect_scope(&["README.md"]), None);
+        assert_eq!(detect_scope(&[]), None);
+    }
+
+    #[test]
+    fn test_detect_language() {
+        let japanese = "diff --git a/src/cache.rs b/src/cache.rs\nindex 1..2 100644\n--- a/src/cache.rs\n+++ b/src/cache.rs\n@@ -1,2 +1,6 @@\n+// キャッシュの有効期限を確認する\n+fn is_fresh(entry: &Entry) -> bool {\n+    // 期限切れのエントリは使わない\n+    entry.expires_at > now()\n+}\n+const MESSAGE: &str = \"キャッシュが見つかりません\";\n";
+        assert_eq!(detect_language(&[japanese]), Some(Language::Ja));
+
+        let english = "diff --git a/src/cache.rs b/src/cache.rs\nindex 1..2 100644\n--- a/src/cache.rs\n+++ b/src/cache.rs\n@@ -1,2 +1,6 @@\n+// Check whether the cache entry has expired\n+fn is_fresh(entry: &Entry) -> bool {\n+    // Expired entries are never used again\n+    entry.expires_at > now()\n+}\n+const MESSAGE: &str = \"The cache entry could not be found\";\n";
+        assert_eq!(detect_language(&[english]), Some(Language::En));
+
+        assert_eq!(detect_language(&[&diff("src/main.rs")]), None);
+
+        // English license headers and commented-out code don't outweigh the
+        // Japanese the authors write their comments in
+        let licensed = "diff --git a/src/cache.rs b/src/cache.rs\nindex 1..2 100644\n--- a/src/cache.rs\n+++ b/src/cache.rs\n@@ -1,2 +1,12 @@\n+// Copyright (c) 2024 The Example Authors. All rights reserved.\n+// SPDX-License-Identifier: Apache-2.0\n+// Licensed under the Apache License, Version 2.0, see the LICENSE file in the project root\n+#[derive(Debug)]\n+struct Entry;\n+// let cached = lookup(&key).unwrap_or_default();\n+// if cached.is_expired() { return None; }\n+// キャッシュの有効期限を確認する\n+fn is_fresh(entry: &Entry) -> bool {\n+    // 期限切れのエントリは使わない\n+    entry.expires_at > now()\n+}\n";
+        assert_eq!(detect_language(&[licensed]), Some(Language::Ja));
+    }
+
+    #[test]
+    fn test_mixed_changes() {
+        let diffs = [diff("README.md"), diff("tests/cli.rs"), diff("src/main.rs")];
+        let diffs = diffs.iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(
+            classify_commit_type(&diffs, &test_patterns(), &config_patterns()),
+            None
+        );
+        assert_eq!(
+            classify_commit_type(&[], &test_patterns(), &config_patterns()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_diff_stats() {
+        let diffs = [
+            "diff --git a/src/lib.rs b/src/lib.rs\nindex 1..2 100644\n--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1,3 +1,4 @@\n-fn foo() {}\n+fn bar() {}\n+fn baz() {}\n same\n@@ -10,2 +11,1 @@\n--- not a header\n-removed\n".to_string(),
+            new_file_diff("docs/guide.md"),
+            "diff --git a/old.txt b/old.txt\ndeleted file mode 100644\nindex 2..0000000\n--- a/old.txt\n+++ /dev/null\n@@ -1,2 +0,0 @@\n-one\n-two\n\\ No newline at end of file\n".to_string(),
+        ];
+        let diffs = diffs.iter().map(String::as_str).collect::<Vec<_>>();
+
+        assert_eq!(
+            DiffStats::of(&diffs),
+            DiffStats {
+                files_changed: 3,
+                insertions: 3,
+                deletions: 5,
+            }
+        );
+        assert_eq!(DiffStats::of(&[]), DiffStats::default());
+    }
+}
@@ -0,0 +1,79 @@
+//! Summarizes git diffs into commit messages with large language models.
+//!
+//! The `gptcommit` binary runs this as a git hook. To generate messages from
+//! another tool, build a [`SummarizationClient`] with a
+//! [`SummarizationClientBuilder`] around your own [`LlmClient`].
+
+#[macro_use]
+extern crate log;
+
+mod actions;
+mod budget;
+mod cache;
+mod cli;
+mod cmd;
+mod diff;
+mod error;
+mod git;
+mod help;
+mod heuristics;
+mod llms;
+mod prompt;
+mod settings;
+mod summarize;
+mod toml;
+mod util;
+
+use clap::Parser;
+use log::LevelFilter;
+use settings::Settings;
+use simple_logger::SimpleLogger;
+
+use crate::cli::Action;
+
+pub use error::{Result, SummarizeError};
+pub use llms::llm_client::{CompletionOptions, LlmClient};
+pub use settings::Language;
+pub use summarize::{CommitMessageOutput, Diff, SummarizationClient, SummarizationClientBuilder};
+
+/// Runs the `gptcommit` command line with the arguments of the process.
+#[doc(hidden)]
+pub async fn run() -> anyhow::Result<()> {
+    let cli_args = cli::GptcommitCLI::parse();
+    SimpleLogger::new()
+        .with_level(if cli_args.verbose {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Warn
+        })
+        // the stage spans report on completion, not when entered and exited
+        .with_module_level("tracing::span", LevelFilter::Off)
+        .env()
+        .init()?;
+    debug!("gptcommit v{}", env!("CARGO_PKG_VERSION"));
+
+    debug!("CLI args: {:?}", cli_args);
+
+    let settings = Settings::new()?;
+    debug!("Settings: {:?}", settings);
+
+    match cli_args.action {
+        Action::Config(cli_args) => actions::config::main(settings, cli_args).await,
+        Action::Install => actions::install::main(settings).await,
+        Action::Doctor => {
+            let settings = settings
+                .with_model_override(cli_args.model.as_deref())
+                .expand_env_vars()?;
+            actions::doctor::main(settings).await
+        }
+        Action::Uninstall => actions::uninstall::main(settings).await,
+        Action::PrepareCommitMsg(mut args) => {
+            args.transcript = cli_args.transcript;
+            let settings = settings
+                .with_model_override(cli_args.model.as_deref())
+                .expand_env_vars()?;
+            actions::prepare_commit_msg::main(settings, *args).await
+        }
+        Action::PostCommit => actions::post_commit::main(settings).await,
+    }
+}
@@ -1,10 +1,89 @@
 use std::fmt::Debug;
 
-use anyhow::Result;
 use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+
+use crate::error::{Result, SummarizeError};
+
+/// Sampling options for a single completion. Unset options use the client's defaults.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompletionOptions {
+    pub temperature: Option<f32>,
+    /// Sampling seed, sent only by clients whose API supports it
+    pub seed: Option<i64>,
+    /// The most tokens the completion may have
+    pub max_tokens: Option<u16>,
+    /// The JSON schema of an object to complete instead of text, requested by
+    /// clients with function calling. Other clients complete the prompt as text.
+    pub json_schema: Option<serde_json::Value>,
+    /// Sequences to stop the completion at, sent only by clients whose API supports them
+    pub stop: Vec<String>,
+}
+
+/// The system prompt followed by the prompt, for clients without message roles.
+pub(crate) fn join_system_prompt(system: &str, prompt: &str) -> String {
+    if system.is_empty() || system.ends_with('\n') {
+        format!("{system}{prompt}")
+    } else {
+        format!("{system}\n\n{prompt}")
+    }
+}
 
 #[async_trait]
 pub trait LlmClient: Debug + Send + Sync {
     /// It takes a prompt as input, and returns the completion using an external Large Language Model.
     async fn completions(&self, prompt: &str) -> Result<String>;
+
+    /// Like `completions`, with sampling options.
+    /// Clients without sampling options ignore them.
+    async fn completions_with_options(
+        &self,
+        prompt: &str,
+        _options: &CompletionOptions,
+    ) -> Result<String> {
+        self.completions(prompt).await
+    }
+
+    /// Like `completions_with_options`, sending the instructions in `system` apart
+    /// from the prompt, as a system message. Clients without message roles send
+    /// them before the prompt.
+    async fn completions_with_system(
+        &self,
+        system: &str,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<String> {
+        self.completions_with_options(&join_system_prompt(system, prompt), options)
+            .await
+    }
+
+    /// Like `completions_with_options`, but yields the completion in pieces as it is generated.
+    /// Clients without streaming support yield the whole completion at once.
+    async fn completions_stream(
+        &self,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<BoxStream<'static, Result<String>>> {
+        let completion = self.completions_with_options(prompt, options).await?;
+        Ok(stream::once(async { Ok(completion) }).boxed())
+    }
+
+    /// Like `completions_stream`, with a system prompt as in `completions_with_system`.
+    async fn completions_stream_with_system(
+        &self,
+        system: &str,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<BoxStream<'static, Result<String>>> {
+        self.completions_stream(&join_system_prompt(system, prompt), options)
+            .await
+    }
+
+    /// It takes an input text, and returns its embedding vector.
+    /// Clients without an embeddings endpoint return an error.
+    async fn embeddings(&self, _input: &str) -> Result<Vec<f32>> {
+        Err(SummarizeError::Provider(
+            "This model provider does not support embeddings.".to_string(),
+        ))
+    }
 }
@@ -0,0 +1,188 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+
+use crate::{error::Result, settings::MockSettings, util};
+
+use super::llm_client::{CompletionOptions, LlmClient};
+
+/// Offline client with deterministic completions, for tests and demos.
+///
+/// Scripted `mock.responses` are returned in the order the prompts arrive,
+/// repeating the last one once they run out. Without a script, the completion
+/// is derived from the kind of prompt.
+#[derive(Debug)]
+pub(crate) struct MockClient {
+    responses: Vec<String>,
+    next_response: AtomicUsize,
+    respond: fn(&str) -> String,
+    prompts: Arc<Mutex<Vec<String>>>,
+    options: Arc<Mutex<Vec<CompletionOptions>>>,
+}
+
+impl Default for MockClient {
+    fn default() -> Self {
+        Self {
+            responses: vec![],
+            next_response: AtomicUsize::new(0),
+            respond: canned_completion,
+            prompts: Default::default(),
+            options: Default::default(),
+        }
+    }
+}
+
+impl MockClient {
+    pub(crate) fn new(settings: MockSettings) -> Self {
+        Self {
+            responses: settings.responses.unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    /// A client answering every prompt with `respond(prompt)`.
+    #[cfg(test)]
+    pub(crate) fn responding_with(respond: fn(&str) -> String) -> Self {
+        Self {
+            respond,
+            ..Default::default()
+        }
+    }
+
+    /// The prompts received so far.
+    #[cfg(test)]
+    pub(crate) fn prompts(&self) -> Arc<Mutex<Vec<String>>> {
+        self.prompts.clone()
+    }
+
+    /// The sampling options of the completions requested so far.
+    #[cfg(test)]
+    pub(crate) fn options(&self) -> Arc<Mutex<Vec<CompletionOptions>>> {
+        self.options.clone()
+    }
+}
+
+#[async_trait]
+impl LlmClient for MockClient {
+    async fn completions(&self, prompt: &str) -> Result<String> {
+        self.completions_with_options(prompt, &CompletionOptions::default())
+            .await
+    }
+
+    async fn completions_with_options(
+        &self,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<String> {
+        self.options.lock().unwrap().push(options.clone());
+        self.prompts.lock().unwrap().push(prompt.to_string());
+        if self.responses.is_empty() {
+            return Ok((self.respond)(prompt));
+        }
+        let idx = self.next_response.fetch_add(1, Ordering::SeqCst);
+        Ok(self.responses[idx.min(self.responses.len() - 1)].clone())
+    }
+
+    /// Streams the completion word by word.
+    async fn completions_stream(
+        &self,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<BoxStream<'static, Result<String>>> {
+        let completion = self.completions_with_options(prompt, options).await?;
+        let words = completion
+            .split_inclusive(' ')
+            .map(|word| Ok(word.to_string()))
+            .collect::<Vec<_>>();
+        Ok(stream::iter(words).boxed())
+    }
+}
+
+/// Answers by recognizing which of the built-in prompts was sent.
+fn canned_completion(prompt: &str) -> String {
+    if prompt.contains("THE COMMIT MESSAGE TITLE:") {
+        "Update the project".to_string()
+    } else if prompt.contains("The label best describing this change:") {
+        "chore".to_string()
+    } else if let Some(message) = prompt
+        .split_once("GIT COMMIT MESSAGE:\n\n###\n")
+        .and_then(|(_, rest)| rest.split_once("\n###"))
+        .map(|(message, _)| message)
+    {
+        // translations keep the message as is
+        message.to_string()
+    } else if let Some((_, file_diffs)) =
+        prompt.rsplit_once("THE LABELED GIT DIFFS TO BE SUMMARIZED:")
+    {
+        file_diffs
+            .lines()
+            .filter(|line| line.starts_with("diff --git "))
+            .filter_map(util::get_file_name_from_diff)
+            .map(|file_name| format!("[{file_name}]\n- Update {file_name}\n"))
+            .collect()
+    } else if let Some(file_name) = prompt
+        .rsplit_once("THE GIT DIFF TO BE SUMMARIZED:")
+        .and_then(|(_, file_diff)| util::get_file_name_from_diff(file_diff))
+    {
+        format!("- Update {file_name}")
+    } else {
+        "- Update the project".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scripted_responses() {
+        let client = MockClient::new(MockSettings {
+            responses: Some(vec!["first".to_string(), "second".to_string()]),
+        });
+
+        assert_eq!(client.completions("a").await.unwrap(), "first");
+        assert_eq!(client.completions("b").await.unwrap(), "second");
+        assert_eq!(client.completions("c").await.unwrap(), "second");
+        assert_eq!(*client.prompts().lock().unwrap(), vec!["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_yields_words() {
+        let client = MockClient::responding_with(|_| "Add a widget".to_string());
+
+        let words = client
+            .completions_stream("", &CompletionOptions::default())
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(words, vec!["Add ", "a ", "widget"]);
+    }
+
+    #[test]
+    fn test_canned_completions() {
+        let file_prompt = crate::prompt::format_prompt(
+            &crate::prompt::PROMPT_TO_SUMMARIZE_DIFF,
+            std::collections::HashMap::from([
+                ("file_diff", "diff --git a/src/main.rs b/src/main.rs\n"),
+                ("commit_message", ""),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(canned_completion(&file_prompt), "- Update src/main.rs");
+
+        let translation_prompt = crate::prompt::format_prompt(
+            crate::prompt::PROMPT_TO_TRANSLATE,
+            std::collections::HashMap::from([
+                ("commit_message", "Add a widget"),
+                ("output_language", "Japanese"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(canned_completion(&translation_prompt), "Add a widget");
+    }
+}
@@ -1,3 +1,9 @@
 pub(crate) mod llm_client;
+pub(crate) mod mock;
+pub(crate) mod offline;
 pub(crate) mod openai;
+pub(crate) mod openrouter;
+#[cfg(test)]
+pub(crate) mod test_server;
 pub(crate) mod tester_foobar;
+pub(crate) mod throttle;
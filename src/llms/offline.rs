@@ -0,0 +1,19 @@
+use async_trait::async_trait;
+
+use crate::error::{Result, SummarizeError};
+
+use super::llm_client::LlmClient;
+
+/// A client for runs that send no prompts, like the offline fallback or
+/// checking the prompt templates. Every completion fails.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OfflineClient;
+
+#[async_trait]
+impl LlmClient for OfflineClient {
+    async fn completions(&self, _prompt: &str) -> Result<String> {
+        Err(SummarizeError::Provider(
+            "No model provider is available offline".to_string(),
+        ))
+    }
+}
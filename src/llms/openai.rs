@@ -1,29 +1,46 @@
-use anyhow::{anyhow, bail, Ok, Result};
+use anyhow::bail;
 use std::fmt;
 use std::fmt::Debug;
 use std::time::Duration;
 
 use async_trait::async_trait;
+use backoff::{backoff::Backoff, ExponentialBackoff};
+use futures::stream::{BoxStream, StreamExt};
 
-use reqwest::{tls, Proxy};
+use reqwest::{tls, Proxy, StatusCode};
 use tiktoken_rs::{async_openai::get_chat_completion_max_tokens, get_completion_max_tokens};
 
-use crate::{settings::OpenAISettings, util::HTTP_USER_AGENT};
+use crate::{
+    error::{Result, SummarizeError},
+    settings::OpenAISettings,
+    util::HTTP_USER_AGENT,
+};
 use async_openai::{
     config::{OpenAIConfig, OPENAI_API_BASE},
     types::{
-        ChatCompletionRequestMessageArgs, CreateChatCompletionRequestArgs,
-        CreateCompletionRequestArgs, Role,
+        ChatCompletionFunctionCall, ChatCompletionFunctionsArgs, ChatCompletionRequestMessageArgs,
+        CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
+        CreateCompletionRequest, CreateCompletionRequestArgs, CreateEmbeddingRequestArgs, Role,
+        Stop,
     },
     Client,
 };
 
-use super::llm_client::LlmClient;
+use super::llm_client::{join_system_prompt, CompletionOptions, LlmClient};
+use super::throttle::Throttle;
 const COMPLETION_TOKEN_LIMIT: usize = 100;
+const EMBEDDINGS_MODEL: &str = "text-embedding-ada-002";
 
 pub(crate) struct OpenAIClient {
     model: String,
     client: Client<OpenAIConfig>,
+    embeddings_client: Client<OpenAIConfig>,
+    api_base: String,
+    api_key: String,
+    http_client: reqwest::Client,
+    /// The retries of `openai.retries`, for requests sent without the API client
+    backoff: Option<ExponentialBackoff>,
+    throttle: Option<Throttle>,
 }
 
 impl Debug for OpenAIClient {
@@ -36,8 +53,10 @@ impl Debug for OpenAIClient {
 
 impl OpenAIClient {
     pub(crate) fn new(settings: OpenAISettings) -> Result<Self, anyhow::Error> {
+        // gateway URLs are often copied with a trailing slash, which would double up in the endpoints
         let api_base = settings
             .api_base
+            .map(|base| base.trim_end_matches('/').to_string())
             .unwrap_or_else(|| OPENAI_API_BASE.to_string());
         let api_key = settings.api_key.unwrap_or_default();
 
@@ -48,7 +67,10 @@ impl OpenAIClient {
         let mut openai_client = Client::<OpenAIConfig>::with_config(openai_config);
 
         if api_base == OPENAI_API_BASE && api_key.is_empty() {
-            bail!("No OpenAI API key found. Please provide a valid API key.");
+            return Err(SummarizeError::MissingApiKey(
+                "No OpenAI API key found. Please provide a valid API key.".to_string(),
+            )
+            .into());
         }
         // TODO make configurable
         let mut http_client = reqwest::Client::builder()
@@ -78,47 +100,102 @@ impl OpenAIClient {
                 http_client = http_client.proxy(Proxy::all(proxy)?);
             }
         }
-        openai_client = openai_client.with_http_client(http_client.build()?);
+        let http_client = http_client.build()?;
+        openai_client = openai_client.with_http_client(http_client.clone());
 
-        if settings.retries.unwrap_or_default() > 0 {
-            let backoff = backoff::ExponentialBackoffBuilder::new()
+        let backoff = (settings.retries.unwrap_or_default() > 0).then(|| {
+            backoff::ExponentialBackoffBuilder::new()
                 .with_max_elapsed_time(Some(std::time::Duration::from_secs(60)))
-                .build();
-            openai_client = openai_client.with_backoff(backoff);
+                .build()
+        });
+        if let Some(backoff) = &backoff {
+            openai_client = openai_client.with_backoff(backoff.clone());
         }
         Ok(Self {
             model,
+            embeddings_client: openai_client.clone(),
             client: openai_client,
+            api_base,
+            api_key,
+            http_client,
+            backoff,
+            throttle: None,
         })
     }
 
+    /// Sends the embeddings requests to `model.embeddings_base_url` instead of
+    /// the `api_base` of the chat completions.
+    pub(crate) fn with_embeddings_base_url(mut self, base_url: &str) -> Self {
+        let config = OpenAIConfig::new()
+            .with_api_base(base_url.trim_end_matches('/'))
+            .with_api_key(&self.api_key);
+        let mut embeddings_client =
+            Client::<OpenAIConfig>::with_config(config).with_http_client(self.http_client.clone());
+        if let Some(backoff) = &self.backoff {
+            embeddings_client = embeddings_client.with_backoff(backoff.clone());
+        }
+        self.embeddings_client = embeddings_client;
+        self
+    }
+
+    /// Slows down chat completion requests when the rate limit headers of
+    /// the previous responses show the budget is nearly spent.
+    pub(crate) fn with_proactive_throttle(mut self) -> Self {
+        self.throttle = Some(Throttle::default());
+        self
+    }
+
     pub(crate) fn should_use_chat_completion(model: &str) -> bool {
         model.to_lowercase().starts_with("gpt-4")
             || model.to_lowercase().starts_with("gpt-3.5-turbo")
     }
 
-    pub(crate) async fn get_completions(&self, prompt: &str) -> Result<String> {
+    fn completion_request(
+        &self,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<CreateCompletionRequest> {
         let prompt_token_limit = get_completion_max_tokens(&self.model, prompt)?;
 
         if prompt_token_limit < COMPLETION_TOKEN_LIMIT {
             let error_msg =
 "Skipping... The diff is too large for the current model. Consider using a model with a larger context window.".to_string();
             warn!("{}", error_msg);
-            bail!(error_msg)
+            return Err(SummarizeError::ContextLengthExceeded(error_msg));
         }
         // Create request using builder pattern
-        let request = CreateCompletionRequestArgs::default()
+        let mut request = CreateCompletionRequestArgs::default();
+        request
             .model(&self.model)
             .prompt(prompt)
-            .max_tokens(prompt_token_limit as u16)
-            .temperature(0.5)
+            .max_tokens(
+                options
+                    .max_tokens
+                    .map_or(prompt_token_limit as u16, |max_tokens| {
+                        max_tokens.min(prompt_token_limit as u16)
+                    }),
+            )
+            .temperature(options.temperature.unwrap_or(0.5))
             .top_p(1.)
             .frequency_penalty(0.)
-            .presence_penalty(0.)
-            .build()?;
+            .presence_penalty(0.);
+        if !options.stop.is_empty() {
+            request.stop(stop_sequences(options));
+        }
+        let request = request.build()?;
 
         debug!("Sending request to OpenAI:\n{:?}", request);
 
+        Ok(request)
+    }
+
+    pub(crate) async fn get_completions(
+        &self,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<String> {
+        let request = self.completion_request(prompt, options)?;
+
         let response = self
             .client
             .completions() // Get the API "group" (completions, images, etc.) from the client
@@ -128,61 +205,539 @@ impl OpenAIClient {
         let completion = response
             .choices
             .first()
-            .ok_or(anyhow!("No completion results returned from OpenAI."))
+            .ok_or_else(no_completion_error)
             .map(|c| c.text.clone());
 
         completion
     }
 
-    pub(crate) async fn get_chat_completions(&self, prompt: &str) -> Result<String> {
-        let messages = [ChatCompletionRequestMessageArgs::default()
-            .role(Role::User)
-            .content(prompt)
-            .build()?];
-        let prompt_token_limit = get_chat_completion_max_tokens(&self.model, &messages)?;
+    fn chat_completion_request(
+        &self,
+        system: Option<&str>,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<CreateChatCompletionRequest> {
+        let request = chat_completion_request(&self.model, system, prompt, options)?;
+        let prompt_token_limit = get_chat_completion_max_tokens(&self.model, &request.messages)?;
 
         if prompt_token_limit < COMPLETION_TOKEN_LIMIT {
             let error_msg =
                 "skipping... diff is too large for the model. Consider using a model with a larger context window.".to_string();
             warn!("{}", error_msg);
-            bail!(error_msg)
+            return Err(SummarizeError::ContextLengthExceeded(error_msg));
         }
 
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(&self.model)
-            .messages(messages)
-            .build()?;
+        Ok(request)
+    }
 
-        let response = self.client.chat().create(request).await?;
+    pub(crate) async fn get_chat_completions(
+        &self,
+        system: Option<&str>,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<String> {
+        let request = self.chat_completion_request(system, prompt, options)?;
+        let response = match &self.throttle {
+            Some(throttle) => {
+                create_chat_completion_throttled(
+                    &self.http_client,
+                    &self.api_base,
+                    &self.api_key,
+                    &request,
+                    throttle,
+                    self.backoff.clone(),
+                )
+                .await?
+            }
+            None => self.client.chat().create(request).await?,
+        };
 
-        if let Some(choice) = response.choices.into_iter().next() {
-            debug!(
-                "{}: Role: {}  Content: {}",
-                choice.index,
-                choice.message.role,
-                choice.message.content.clone().unwrap_or_default()
-            );
+        first_chat_choice(response)
+    }
+
+    /// Completes the prompt with the chat API when the model supports it, which
+    /// gets the system prompt as its own message, or else the completions API.
+    async fn complete(
+        &self,
+        system: Option<&str>,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<String> {
+        if let Some(seed) = options.seed {
+            debug!("OpenAI client doesn't send seeds, ignoring seed {seed}");
+        }
+        let completion = if OpenAIClient::should_use_chat_completion(&self.model) {
+            self.get_chat_completions(system, prompt, options).await?
+        } else {
+            let prompt = join_system_prompt(system.unwrap_or_default(), prompt);
+            self.get_completions(&prompt, options).await?
+        };
+        Ok(completion.trim().to_string())
+    }
 
-            return choice
-                .message
-                .content
-                .ok_or(anyhow!("No completion results returned from OpenAI."));
+    async fn complete_stream(
+        &self,
+        system: Option<&str>,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<BoxStream<'static, Result<String>>> {
+        if let Some(throttle) = &self.throttle {
+            throttle.wait().await;
+        }
+        if OpenAIClient::should_use_chat_completion(&self.model) {
+            let request = self.chat_completion_request(system, prompt, options)?;
+            let stream = self.client.chat().create_stream(request).await?;
+            Ok(stream
+                .map(|response| {
+                    Ok(response?
+                        .choices
+                        .into_iter()
+                        .filter_map(|choice| choice.delta.content)
+                        .collect::<String>())
+                })
+                .boxed())
+        } else {
+            let prompt = join_system_prompt(system.unwrap_or_default(), prompt);
+            let request = self.completion_request(&prompt, options)?;
+            let stream = self.client.completions().create_stream(request).await?;
+            Ok(stream
+                .map(|response| {
+                    Ok(response?
+                        .choices
+                        .into_iter()
+                        .map(|choice| choice.text)
+                        .collect::<String>())
+                })
+                .boxed())
         }
+    }
+}
+
+/// Builds a chat completion request sending the prompt as a user message,
+/// preceded by the system prompt if any. Shared by the clients of
+/// OpenAI-compatible APIs.
+pub(super) fn chat_completion_request(
+    model: &str,
+    system: Option<&str>,
+    prompt: &str,
+    options: &CompletionOptions,
+) -> Result<CreateChatCompletionRequest> {
+    let mut messages = Vec::new();
+    if let Some(system) = system.filter(|system| !system.is_empty()) {
+        messages.push(
+            ChatCompletionRequestMessageArgs::default()
+                .role(Role::System)
+                .content(system)
+                .build()?,
+        );
+    }
+    messages.push(
+        ChatCompletionRequestMessageArgs::default()
+            .role(Role::User)
+            .content(prompt)
+            .build()?,
+    );
+
+    let mut request = CreateChatCompletionRequestArgs::default();
+    request.model(model).messages(messages);
+    if let Some(temperature) = options.temperature {
+        request.temperature(temperature);
+    }
+    if let Some(max_tokens) = options.max_tokens {
+        request.max_tokens(max_tokens);
+    }
+    if !options.stop.is_empty() {
+        request.stop(stop_sequences(options));
+    }
+    if let Some(json_schema) = &options.json_schema {
+        request
+            .functions([ChatCompletionFunctionsArgs::default()
+                .name(RESPOND_FUNCTION)
+                .parameters(json_schema.clone())
+                .build()?])
+            .function_call(ChatCompletionFunctionCall::Object(
+                serde_json::json!({ "name": RESPOND_FUNCTION }),
+            ));
+    }
+
+    Ok(request.build()?)
+}
+
+/// The stop sequences of the request, of which the API takes up to 4. The
+/// summarizer cuts the completion at the others.
+fn stop_sequences(options: &CompletionOptions) -> Stop {
+    Stop::StringArray(options.stop.iter().take(4).cloned().collect())
+}
+
+/// The function a completion with a JSON schema is made to call, whose
+/// arguments are the completed object.
+const RESPOND_FUNCTION: &str = "respond";
 
-        bail!("No completion results returned from OpenAI.")
+/// The content of the first choice of a chat completion response, or the
+/// arguments of the function it called.
+/// Sends the chat completion request directly, since the API client doesn't
+/// expose the rate limit headers of its responses. Every attempt, retries
+/// included, waits for the throttle first. Rate limited and server errors are
+/// retried with the backoff, if any.
+pub(super) async fn create_chat_completion_throttled(
+    http_client: &reqwest::Client,
+    api_base: &str,
+    api_key: &str,
+    request: &CreateChatCompletionRequest,
+    throttle: &Throttle,
+    mut backoff: Option<ExponentialBackoff>,
+) -> Result<CreateChatCompletionResponse> {
+    if let Some(backoff) = backoff.as_mut() {
+        backoff.reset();
+    }
+    loop {
+        throttle.wait().await;
+        let response = http_client
+            .post(format!("{api_base}/chat/completions"))
+            .bearer_auth(api_key)
+            .json(request)
+            .send()
+            .await?;
+        throttle.observe(response.headers());
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response.json().await?);
+        }
+        let retryable = status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+        match backoff.as_mut().and_then(Backoff::next_backoff) {
+            Some(delay) if retryable => {
+                warn!("the request failed with {status}, retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
+            }
+            _ => {
+                return Err(SummarizeError::from_response(
+                    status.as_u16(),
+                    &response.text().await?,
+                ))
+            }
+        }
     }
 }
 
+pub(super) fn first_chat_choice(response: CreateChatCompletionResponse) -> Result<String> {
+    if let Some(choice) = response.choices.into_iter().next() {
+        debug!(
+            "{}: Role: {}  Content: {}",
+            choice.index,
+            choice.message.role,
+            choice.message.content.clone().unwrap_or_default()
+        );
+
+        let message = choice.message;
+        return match message.function_call {
+            Some(call) if message.content.as_deref().unwrap_or_default().is_empty() => {
+                Ok(call.arguments)
+            }
+            _ => message.content.ok_or_else(no_completion_error),
+        };
+    }
+
+    Err(no_completion_error())
+}
+
+fn no_completion_error() -> SummarizeError {
+    SummarizeError::Provider("No completion results returned from OpenAI.".to_string())
+}
+
 #[async_trait]
 impl LlmClient for OpenAIClient {
     /// Sends a request to OpenAI's API to get a text completion.
     /// It takes a prompt as input, and returns the completion.
     async fn completions(&self, prompt: &str) -> Result<String> {
-        let completion = if OpenAIClient::should_use_chat_completion(&self.model) {
-            self.get_chat_completions(prompt).await?
-        } else {
-            self.get_completions(prompt).await?
+        self.completions_with_options(prompt, &CompletionOptions::default())
+            .await
+    }
+    /// Sends the completion request with the given sampling options.
+    /// The API client doesn't support seeds, so they are ignored.
+    async fn completions_with_options(
+        &self,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<String> {
+        self.complete(None, prompt, options).await
+    }
+    /// Sends the system prompt as a system message to chat models, and before
+    /// the prompt to the others.
+    async fn completions_with_system(
+        &self,
+        system: &str,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<String> {
+        self.complete(Some(system), prompt, options).await
+    }
+    /// Streams the completion from OpenAI's API as it is generated.
+    async fn completions_stream(
+        &self,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<BoxStream<'static, Result<String>>> {
+        self.complete_stream(None, prompt, options).await
+    }
+    async fn completions_stream_with_system(
+        &self,
+        system: &str,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<BoxStream<'static, Result<String>>> {
+        self.complete_stream(Some(system), prompt, options).await
+    }
+
+    /// Embeds the input with OpenAI's embeddings API, which may be served from its own base URL.
+    async fn embeddings(&self, input: &str) -> Result<Vec<f32>> {
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(EMBEDDINGS_MODEL)
+            .input(input)
+            .build()?;
+
+        let response = self.embeddings_client.embeddings().create(request).await?;
+
+        response
+            .data
+            .into_iter()
+            .next()
+            .map(|embedding| embedding.embedding)
+            .ok_or_else(|| {
+                SummarizeError::Provider("No embeddings returned from OpenAI.".to_string())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llms::test_server::serve_once;
+
+    #[tokio::test]
+    async fn test_embeddings_use_embeddings_base_url() {
+        let (embeddings_base, request) = serve_once(
+            200,
+            &[],
+            r#"{"object":"list","model":"text-embedding-ada-002","data":[{"index":0,"object":"embedding","embedding":[0.5,-0.5]}],"usage":{"prompt_tokens":1,"total_tokens":1}}"#,
+        )
+        .await;
+        let client = OpenAIClient::new(OpenAISettings {
+            api_base: Some("http://127.0.0.1:9/v1".to_string()),
+            api_key: Some("sk-test".to_string()),
+            model: Some("gpt-3.5-turbo".to_string()),
+            retries: Some(0),
+            proxy: None,
+        })
+        .unwrap()
+        .with_embeddings_base_url(&embeddings_base);
+
+        let embedding = client.embeddings("hello").await.unwrap();
+
+        assert_eq!(embedding, vec![0.5, -0.5]);
+        let request = request.await.unwrap();
+        assert_eq!(request.request_line, "POST /v1/embeddings HTTP/1.1");
+        assert_eq!(request.header("authorization"), Some("Bearer sk-test"));
+        assert_eq!(request.json()["input"], "hello");
+    }
+
+    #[tokio::test]
+    async fn test_requests_use_api_base() {
+        let (api_base, request) = serve_once(
+            200,
+            &[],
+            r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-4","choices":[{"index":0,"message":{"role":"assistant","content":"Fix a typo"},"finish_reason":"stop"}]}"#,
+        )
+        .await;
+        let client = OpenAIClient::new(OpenAISettings {
+            api_base: Some(format!("{api_base}/")),
+            api_key: Some("sk-gateway".to_string()),
+            model: Some("gpt-4".to_string()),
+            retries: Some(0),
+            proxy: None,
+        })
+        .unwrap();
+
+        let completion = client.completions("hello").await.unwrap();
+
+        assert_eq!(completion, "Fix a typo");
+        let request = request.await.unwrap();
+        assert_eq!(request.request_line, "POST /v1/chat/completions HTTP/1.1");
+        assert_eq!(request.header("authorization"), Some("Bearer sk-gateway"));
+        assert_eq!(request.json()["model"], "gpt-4");
+    }
+
+    #[tokio::test]
+    async fn test_system_prompt_is_sent_as_its_own_message() {
+        let (api_base, request) = serve_once(
+            200,
+            &[],
+            r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-4","choices":[{"index":0,"message":{"role":"assistant","content":"Fix a typo"},"finish_reason":"stop"}]}"#,
+        )
+        .await;
+        let client = OpenAIClient::new(OpenAISettings {
+            api_base: Some(api_base),
+            api_key: Some("sk-test".to_string()),
+            model: Some("gpt-4".to_string()),
+            retries: Some(0),
+            proxy: None,
+        })
+        .unwrap();
+
+        let completion = client
+            .completions_with_system(
+                "You write commit titles.",
+                "THE DIFF: ...",
+                &CompletionOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(completion, "Fix a typo");
+        let messages = request.await.unwrap().json()["messages"].clone();
+        assert_eq!(messages.as_array().unwrap().len(), 2);
+        assert_eq!(messages[0]["role"], "system");
+        assert_eq!(messages[0]["content"], "You write commit titles.");
+        assert_eq!(messages[1]["role"], "user");
+        assert_eq!(messages[1]["content"], "THE DIFF: ...");
+    }
+
+    #[tokio::test]
+    async fn test_proactive_throttle_reads_rate_limit_headers() {
+        let (api_base, request) = serve_once(
+            200,
+            &[
+                ("x-ratelimit-remaining-requests", "0"),
+                ("x-ratelimit-reset-requests", "300ms"),
+            ],
+            r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-4","choices":[{"index":0,"message":{"role":"assistant","content":"Fix a typo"},"finish_reason":"stop"}]}"#,
+        )
+        .await;
+        let client = OpenAIClient::new(OpenAISettings {
+            api_base: Some(api_base),
+            api_key: Some("sk-test".to_string()),
+            model: Some("gpt-4".to_string()),
+            retries: Some(0),
+            proxy: None,
+        })
+        .unwrap()
+        .with_proactive_throttle();
+
+        let completion = client.completions("hello").await.unwrap();
+
+        assert_eq!(completion, "Fix a typo");
+        assert_eq!(
+            request.await.unwrap().header("authorization"),
+            Some("Bearer sk-test")
+        );
+        let start = std::time::Instant::now();
+        client.throttle.as_ref().unwrap().wait().await;
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    async fn completion_error(status: u16, body: &str) -> SummarizeError {
+        let (api_base, _) = serve_once(status, &[], body).await;
+        let client = OpenAIClient::new(OpenAISettings {
+            api_base: Some(api_base),
+            api_key: Some("sk-test".to_string()),
+            model: Some("gpt-4".to_string()),
+            retries: Some(0),
+            proxy: None,
+        })
+        .unwrap();
+
+        client.completions("hello").await.unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn test_error_responses_map_to_summarize_errors() {
+        let error = completion_error(
+            401,
+            r#"{"error":{"message":"Incorrect API key provided: sk-test.","type":"invalid_request_error","param":null,"code":"invalid_api_key"}}"#,
+        )
+        .await;
+        assert!(matches!(error, SummarizeError::Auth(_)), "{error:?}");
+
+        let error = completion_error(
+            400,
+            r#"{"error":{"message":"This model's maximum context length is 8192 tokens.","type":"invalid_request_error","param":"messages","code":"context_length_exceeded"}}"#,
+        )
+        .await;
+        assert!(
+            matches!(error, SummarizeError::ContextLengthExceeded(_)),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_completion_options_reach_request() {
+        let (api_base, request) = serve_once(
+            200,
+            &[],
+            r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-3.5-turbo","choices":[{"index":0,"message":{"role":"assistant","content":" Fix a typo "},"finish_reason":"stop"}]}"#,
+        )
+        .await;
+        let client = OpenAIClient::new(OpenAISettings {
+            api_base: Some(api_base),
+            api_key: Some("sk-test".to_string()),
+            model: Some("gpt-3.5-turbo".to_string()),
+            retries: Some(0),
+            proxy: None,
+        })
+        .unwrap();
+        let options = CompletionOptions {
+            temperature: Some(0.0),
+            seed: Some(0),
+            max_tokens: Some(30),
+            json_schema: None,
+            stop: vec!["\n\n".to_string()],
         };
-        Ok(completion.trim().to_string())
+
+        let completion = client
+            .completions_with_options("hello", &options)
+            .await
+            .unwrap();
+
+        assert_eq!(completion, "Fix a typo");
+        let body = request.await.unwrap().json();
+        assert_eq!(body["temperature"], 0.0);
+        assert_eq!(body["max_tokens"], 30);
+        assert_eq!(body["stop"], serde_json::json!(["\n\n"]));
+    }
+
+    #[tokio::test]
+    async fn test_json_schema_is_completed_through_function_call() {
+        let (api_base, request) = serve_once(
+            200,
+            &[],
+            r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-3.5-turbo","choices":[{"index":0,"message":{"role":"assistant","content":null,"function_call":{"name":"respond","arguments":"{\"type\":\"feat\"}"}},"finish_reason":"stop"}]}"#,
+        )
+        .await;
+        let client = OpenAIClient::new(OpenAISettings {
+            api_base: Some(api_base),
+            api_key: Some("sk-test".to_string()),
+            model: Some("gpt-3.5-turbo".to_string()),
+            retries: Some(0),
+            proxy: None,
+        })
+        .unwrap();
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "type": { "type": "string" } },
+        });
+        let options = CompletionOptions {
+            json_schema: Some(schema.clone()),
+            ..Default::default()
+        };
+
+        let completion = client
+            .completions_with_options("label the change", &options)
+            .await
+            .unwrap();
+
+        assert_eq!(completion, r#"{"type":"feat"}"#);
+        let body = request.await.unwrap().json();
+        assert_eq!(body["functions"][0]["name"], "respond");
+        assert_eq!(body["functions"][0]["parameters"], schema);
+        assert_eq!(body["function_call"]["name"], "respond");
     }
 }
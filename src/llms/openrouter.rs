@@ -0,0 +1,235 @@
+use anyhow::bail;
+use std::fmt;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use async_openai::{config::OpenAIConfig, Client};
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use reqwest::header::{HeaderMap, HeaderValue};
+
+use crate::{
+    error::{Result, SummarizeError},
+    settings::{OpenRouterSettings, OPENROUTER_API_BASE},
+    util::HTTP_USER_AGENT,
+};
+
+use super::{
+    llm_client::{CompletionOptions, LlmClient},
+    openai::{chat_completion_request, create_chat_completion_throttled, first_chat_choice},
+    throttle::Throttle,
+};
+
+/// Client for OpenRouter, which routes OpenAI-style chat completions to many models.
+pub(crate) struct OpenRouterClient {
+    model: String,
+    client: Client<OpenAIConfig>,
+    api_base: String,
+    api_key: String,
+    http_client: reqwest::Client,
+    throttle: Option<Throttle>,
+}
+
+impl Debug for OpenRouterClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpenRouterClient")
+            .field("model", &self.model)
+            .finish()
+    }
+}
+
+impl OpenRouterClient {
+    pub(crate) fn new(settings: OpenRouterSettings) -> anyhow::Result<Self> {
+        let api_base = settings
+            .api_base
+            .filter(|base| !base.is_empty())
+            .map(|base| base.trim_end_matches('/').to_string())
+            .unwrap_or_else(|| OPENROUTER_API_BASE.to_string());
+        let api_key = settings.api_key.unwrap_or_default();
+        if api_key.is_empty() {
+            return Err(SummarizeError::MissingApiKey(
+                "No OpenRouter API key found. Please provide a valid API key.".to_string(),
+            )
+            .into());
+        }
+        let model = settings.model.unwrap_or_default();
+        if model.is_empty() {
+            bail!("No OpenRouter model configured. Please choose a valid model to use.");
+        }
+
+        // optional app attribution, see https://openrouter.ai/docs#headers
+        let mut headers = HeaderMap::new();
+        if let Some(referer) = settings.referer.filter(|referer| !referer.is_empty()) {
+            headers.insert("HTTP-Referer", HeaderValue::from_str(&referer)?);
+        }
+        if let Some(title) = settings.title.filter(|title| !title.is_empty()) {
+            headers.insert("X-Title", HeaderValue::from_str(&title)?);
+        }
+        let http_client = reqwest::Client::builder()
+            .gzip(true)
+            .brotli(true)
+            .timeout(Duration::from_secs(60))
+            .user_agent(HTTP_USER_AGENT)
+            .default_headers(headers)
+            .build()?;
+
+        let config = OpenAIConfig::new()
+            .with_api_base(&api_base)
+            .with_api_key(&api_key);
+        let client = Client::with_config(config).with_http_client(http_client.clone());
+        Ok(Self {
+            model,
+            client,
+            api_base,
+            api_key,
+            http_client,
+            throttle: None,
+        })
+    }
+
+    /// Slows down requests when the rate limit headers of the previous
+    /// responses show the budget is nearly spent.
+    pub(crate) fn with_proactive_throttle(mut self) -> Self {
+        self.throttle = Some(Throttle::default());
+        self
+    }
+}
+
+impl OpenRouterClient {
+    async fn chat_completion(
+        &self,
+        system: Option<&str>,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<String> {
+        let request = chat_completion_request(&self.model, system, prompt, options)?;
+        let response = match &self.throttle {
+            Some(throttle) => {
+                create_chat_completion_throttled(
+                    &self.http_client,
+                    &self.api_base,
+                    &self.api_key,
+                    &request,
+                    throttle,
+                    None,
+                )
+                .await?
+            }
+            None => self.client.chat().create(request).await?,
+        };
+        Ok(first_chat_choice(response)?.trim().to_string())
+    }
+}
+
+#[async_trait]
+impl LlmClient for OpenRouterClient {
+    async fn completions(&self, prompt: &str) -> Result<String> {
+        self.completions_with_options(prompt, &CompletionOptions::default())
+            .await
+    }
+
+    /// Sends the prompt as a chat completion to the configured model.
+    /// The API client doesn't support seeds, so they are ignored.
+    async fn completions_with_options(
+        &self,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<String> {
+        self.chat_completion(None, prompt, options).await
+    }
+
+    /// Sends the system prompt as a system message before the prompt.
+    async fn completions_with_system(
+        &self,
+        system: &str,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<String> {
+        self.chat_completion(Some(system), prompt, options).await
+    }
+
+    async fn completions_stream_with_system(
+        &self,
+        system: &str,
+        prompt: &str,
+        options: &CompletionOptions,
+    ) -> Result<BoxStream<'static, Result<String>>> {
+        let completion = self.chat_completion(Some(system), prompt, options).await?;
+        Ok(stream::once(async { Ok(completion) }).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llms::test_server::serve_once;
+
+    #[tokio::test]
+    async fn test_attribution_headers_and_model_are_sent() {
+        let (api_base, request) = serve_once(
+            200,
+            &[],
+            r#"{"id":"gen-1","object":"chat.completion","created":0,"model":"anthropic/claude-3.5-sonnet","choices":[{"index":0,"message":{"role":"assistant","content":"Fix a typo"},"finish_reason":"stop"}]}"#,
+        )
+        .await;
+        let client = OpenRouterClient::new(OpenRouterSettings {
+            api_base: Some(api_base),
+            api_key: Some("sk-or-test".to_string()),
+            model: Some("anthropic/claude-3.5-sonnet".to_string()),
+            referer: Some("https://github.com/zurawiki/gptcommit".to_string()),
+            title: Some("gptcommit".to_string()),
+        })
+        .unwrap();
+
+        let completion = client.completions("hello").await.unwrap();
+
+        assert_eq!(completion, "Fix a typo");
+        let request = request.await.unwrap();
+        assert_eq!(request.request_line, "POST /v1/chat/completions HTTP/1.1");
+        assert_eq!(request.header("authorization"), Some("Bearer sk-or-test"));
+        assert_eq!(
+            request.header("http-referer"),
+            Some("https://github.com/zurawiki/gptcommit")
+        );
+        assert_eq!(request.header("x-title"), Some("gptcommit"));
+        assert_eq!(request.json()["model"], "anthropic/claude-3.5-sonnet");
+    }
+
+    #[tokio::test]
+    async fn test_proactive_throttle_reads_rate_limit_headers() {
+        let (api_base, _) = serve_once(
+            200,
+            &[
+                ("x-ratelimit-remaining-requests", "0"),
+                ("x-ratelimit-reset-requests", "300ms"),
+            ],
+            r#"{"id":"gen-1","object":"chat.completion","created":0,"model":"anthropic/claude-3.5-sonnet","choices":[{"index":0,"message":{"role":"assistant","content":"Fix a typo"},"finish_reason":"stop"}]}"#,
+        )
+        .await;
+        let client = OpenRouterClient::new(OpenRouterSettings {
+            api_base: Some(api_base),
+            api_key: Some("sk-or-test".to_string()),
+            model: Some("anthropic/claude-3.5-sonnet".to_string()),
+            ..Default::default()
+        })
+        .unwrap()
+        .with_proactive_throttle();
+
+        let completion = client.completions("hello").await.unwrap();
+
+        assert_eq!(completion, "Fix a typo");
+        let start = std::time::Instant::now();
+        client.throttle.as_ref().unwrap().wait().await;
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_requires_api_key() {
+        let err = OpenRouterClient::new(OpenRouterSettings {
+            model: Some("anthropic/claude-3.5-sonnet".to_string()),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("No OpenRouter API key found"));
+    }
+}
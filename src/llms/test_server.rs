@@ -0,0 +1,96 @@
+//! A minimal HTTP server for asserting on the requests sent by LLM clients.
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    task::JoinHandle,
+};
+
+/// A request as received by the test server.
+#[derive(Debug)]
+pub(crate) struct RecordedRequest {
+    /// The request line, e.g. `POST /v1/embeddings HTTP/1.1`
+    pub request_line: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+/// Serves a single request with the given status, headers and JSON body.
+///
+/// Returns the base URL of the server and a handle resolving to the received request.
+pub(crate) async fn serve_once(
+    status: u16,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> (String, JoinHandle<RecordedRequest>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}/v1", listener.local_addr().unwrap());
+
+    let mut response = format!(
+        "HTTP/1.1 {status} Test\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+
+    let handle = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut raw = Vec::new();
+        let mut buf = [0u8; 4096];
+        let request = loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            raw.extend_from_slice(&buf[..n]);
+            if let Some(request) = parse_request(&raw) {
+                break request;
+            }
+            assert!(n > 0, "connection closed before the request was complete");
+        };
+        socket.write_all(response.as_bytes()).await.unwrap();
+        socket.shutdown().await.ok();
+        request
+    });
+
+    (base_url, handle)
+}
+
+/// Parses a complete request out of the raw bytes, or returns None if more bytes are needed.
+fn parse_request(raw: &[u8]) -> Option<RecordedRequest> {
+    let raw = String::from_utf8_lossy(raw);
+    let (head, body) = raw.split_once("\r\n\r\n")?;
+    let mut lines = head.lines();
+    let request_line = lines.next()?.to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    if body.len() < content_length {
+        return None;
+    }
+
+    Some(RecordedRequest {
+        request_line,
+        headers,
+        body: body.to_string(),
+    })
+}
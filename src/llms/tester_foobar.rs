@@ -1,7 +1,7 @@
-use anyhow::Result;
-
 use async_trait::async_trait;
 
+use crate::error::Result;
+
 #[cfg(test)]
 use async_std::task;
 
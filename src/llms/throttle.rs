@@ -0,0 +1,204 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+use tokio::time::Instant;
+
+/// Remaining requests at or below which dispatch slows down.
+const LOW_REMAINING_REQUESTS: u64 = 1;
+/// Remaining tokens at or below which dispatch slows down, about one file summary.
+const LOW_REMAINING_TOKENS: u64 = 2000;
+/// Delay when the budget is low but the provider didn't say when it resets.
+const DEFAULT_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound for a single delay, so a long reset window doesn't stall the hook.
+const MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// The rate limit budget reported by the provider in its last response.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct RateLimit {
+    remaining_requests: Option<u64>,
+    remaining_tokens: Option<u64>,
+    reset_requests: Option<Duration>,
+    reset_tokens: Option<Duration>,
+}
+
+/// Slows down dispatch before the provider's hard rate limit is hit, based on
+/// the `x-ratelimit-*` headers of the previous responses. The requests sent
+/// concurrently share it, so they all wait for the same reset.
+#[derive(Debug, Default)]
+pub(crate) struct Throttle {
+    /// When the next request may be sent, if the budget is nearly spent
+    next_request: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    /// Records the rate limit budget from the headers of a response.
+    pub(crate) fn observe(&self, headers: &HeaderMap) {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let rate_limit = RateLimit {
+            remaining_requests: header("x-ratelimit-remaining-requests")
+                .and_then(|value| value.parse().ok()),
+            remaining_tokens: header("x-ratelimit-remaining-tokens")
+                .and_then(|value| value.parse().ok()),
+            reset_requests: header("x-ratelimit-reset-requests").and_then(parse_reset),
+            reset_tokens: header("x-ratelimit-reset-tokens").and_then(parse_reset),
+        };
+        if rate_limit == RateLimit::default() {
+            return;
+        }
+        debug!(
+            "rate limit: {} requests and {} tokens remaining",
+            display_remaining(rate_limit.remaining_requests),
+            display_remaining(rate_limit.remaining_tokens),
+        );
+        if let Some(delay) = rate_limit.delay() {
+            let until = Instant::now() + delay;
+            let mut next_request = self.next_request.lock().unwrap();
+            *next_request = Some(next_request.map_or(until, |next| next.max(until)));
+        }
+    }
+
+    /// Waits before the next request when the remaining budget is nearly spent,
+    /// until the reset the previous responses reported.
+    pub(crate) async fn wait(&self) {
+        loop {
+            let next_request = *self.next_request.lock().unwrap();
+            match next_request {
+                Some(until) if until > Instant::now() => {
+                    debug!(
+                        "rate limit nearly reached, waiting {:?} before the next request",
+                        until - Instant::now()
+                    );
+                    // a response received meanwhile may push the reset further
+                    tokio::time::sleep_until(until).await;
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+impl RateLimit {
+    fn delay(&self) -> Option<Duration> {
+        let requests_low = self
+            .remaining_requests
+            .is_some_and(|remaining| remaining <= LOW_REMAINING_REQUESTS);
+        let tokens_low = self
+            .remaining_tokens
+            .is_some_and(|remaining| remaining <= LOW_REMAINING_TOKENS);
+        let delay = match (requests_low, tokens_low) {
+            (false, false) => return None,
+            (true, false) => self.reset_requests,
+            (false, true) => self.reset_tokens,
+            (true, true) => self.reset_requests.max(self.reset_tokens),
+        };
+        Some(delay.unwrap_or(DEFAULT_DELAY).min(MAX_DELAY))
+    }
+}
+
+fn display_remaining(remaining: Option<u64>) -> String {
+    remaining.map_or("unknown".to_string(), |remaining| remaining.to_string())
+}
+
+/// Parses reset durations like `1s`, `6m0s` or `20ms`.
+fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..number_len].parse().ok()?;
+        rest = &rest[number_len..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit_len] {
+            "ms" => number / 1000.,
+            "s" => number,
+            "m" => number * 60.,
+            "h" => number * 3600.,
+            _ => return None,
+        };
+        total += Duration::from_secs_f64(seconds);
+        rest = &rest[unit_len..];
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_slows_when_budget_is_nearly_spent() {
+        let throttle = Throttle::default();
+        throttle.observe(&headers(&[
+            ("x-ratelimit-remaining-requests", "0"),
+            ("x-ratelimit-remaining-tokens", "90000"),
+            ("x-ratelimit-reset-requests", "300ms"),
+        ]));
+
+        let start = Instant::now();
+        throttle.wait().await;
+
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_wait_for_the_same_reset() {
+        let throttle = Throttle::default();
+        throttle.observe(&headers(&[
+            ("x-ratelimit-remaining-tokens", "100"),
+            ("x-ratelimit-reset-tokens", "300ms"),
+        ]));
+
+        let start = Instant::now();
+        let waited = |_| async {
+            throttle.wait().await;
+            start.elapsed()
+        };
+        let waits = futures::future::join_all((0..3).map(waited)).await;
+
+        assert!(waits
+            .iter()
+            .all(|waited| *waited >= Duration::from_millis(300)));
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_continues_with_budget_left() {
+        let throttle = Throttle::default();
+        throttle.observe(&headers(&[
+            ("x-ratelimit-remaining-requests", "2999"),
+            ("x-ratelimit-remaining-tokens", "90000"),
+            ("x-ratelimit-reset-requests", "20s"),
+        ]));
+
+        let start = Instant::now();
+        throttle.wait().await;
+        Throttle::default().wait().await;
+
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_parse_reset() {
+        assert_eq!(parse_reset("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_reset("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_reset("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_reset("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_reset("soon"), None);
+    }
+}
@@ -1,18 +1,608 @@
-use tera::{Context, Error};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use tera::ast::{Expr, ExprVal, Node};
+use tera::{Context, Error, Template};
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
 use tera::Tera;
 
+use crate::error::{Result, SummarizeError};
+use crate::llms::llm_client::join_system_prompt;
+
 pub fn format_prompt(prompt: &str, map: HashMap<&str, &str>) -> Result<String, Error> {
     let context = Context::from_serialize(map)?;
 
     Tera::one_off(prompt, &context, false)
 }
 
+/// A rendered prompt: the static instructions its template starts with, sent
+/// as a system message by clients that support it, and the rest.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Prompt {
+    pub(crate) system: String,
+    pub(crate) user: String,
+}
+
+impl Prompt {
+    /// The whole prompt, as sent to clients without a system prompt.
+    pub(crate) fn joined(&self) -> String {
+        join_system_prompt(&self.system, &self.user)
+    }
+
+    /// The prompt with the preamble of `prompt.preamble` before its instructions,
+    /// in the system prompt.
+    pub(crate) fn with_preamble(mut self, preamble: &str) -> Self {
+        if self.system.is_empty() {
+            self.system = preamble.to_string();
+        } else if !preamble.is_empty() {
+            self.system = join_system_prompt(preamble, &self.system);
+        }
+        self
+    }
+}
+
+/// Renders the prompt like [`format_prompt`], splitting off the lines before
+/// the first tag, which don't depend on the variables, as its system prompt.
+pub(crate) fn render_prompt(prompt: &str, map: HashMap<&str, &str>) -> Result<Prompt, Error> {
+    let (system, rest) = split_system_prompt(prompt);
+    Ok(Prompt {
+        system: system.to_string(),
+        user: format_prompt(rest, map)?,
+    })
+}
+
+/// Renders the prompt like [`render_prompt`], for variables that aren't all
+/// strings, like the list of examples of the conventional commit prefix prompt.
+pub(crate) fn render_prompt_with(prompt: &str, context: &Context) -> Result<Prompt, Error> {
+    let (system, rest) = split_system_prompt(prompt);
+    Ok(Prompt {
+        system: system.to_string(),
+        user: Tera::one_off(rest, context, false)?,
+    })
+}
+
+/// Splits the prompt before the line with its first tag. A prompt without
+/// any tag is left whole, as the user prompt.
+fn split_system_prompt(prompt: &str) -> (&str, &str) {
+    let system_len = prompt
+        .split_inclusive('\n')
+        .take_while(|line| !["{{", "{%", "{#"].iter().any(|tag| line.contains(tag)))
+        .map(str::len)
+        .sum::<usize>();
+    let (system, rest) = prompt.split_at(system_len);
+    if rest.trim().is_empty() {
+        return ("", prompt);
+    }
+    (system, rest)
+}
+
+/// Resolves a prompt setting: a `file:<path>` value is replaced by the contents
+/// of the file, relative paths being resolved from the working directory.
+/// Fails, naming the stage, when the file can't be read, the template doesn't
+/// compile or it references a variable other than the `variables` the stage provides.
+pub(crate) fn load_prompt(stage: &str, prompt: String, variables: &[&str]) -> Result<String> {
+    let prompt = read_prompt(stage, prompt)?;
+    let template = Template::new(stage, None, &prompt).map_err(|e| {
+        SummarizeError::Template(format!("prompt.{stage} is not a valid template: {e:?}"))
+    })?;
+    let mut referenced = BTreeSet::new();
+    collect_variables(&template.ast, &mut HashSet::new(), &mut referenced);
+    if let Some(unknown) = referenced
+        .iter()
+        .find(|variable| !variables.contains(&variable.as_str()))
+    {
+        return Err(SummarizeError::Template(format!(
+            "prompt.{stage} references the unknown variable `{unknown}`. Available variables: {}",
+            variables.join(", ")
+        )));
+    }
+    Ok(prompt)
+}
+
+/// Resolves a prompt setting that isn't a template, like `prompt.preamble`,
+/// reading a `file:<path>` value like [`load_prompt`].
+pub(crate) fn read_prompt(stage: &str, prompt: String) -> Result<String> {
+    match prompt.strip_prefix("file:") {
+        Some(path) => fs::read_to_string(path.trim()).map_err(|e| {
+            SummarizeError::Template(format!(
+                "Could not read the prompt.{stage} file {path}: {e}"
+            ))
+        }),
+        None => Ok(prompt),
+    }
+}
+
+/// Collects the context variables referenced by the nodes, skipping the
+/// variables bound by the template itself with `set` and `for`.
+fn collect_variables(
+    nodes: &[Node],
+    bound: &mut HashSet<String>,
+    referenced: &mut BTreeSet<String>,
+) {
+    for node in nodes {
+        match node {
+            Node::VariableBlock(_, expr) => collect_expr_variables(expr, bound, referenced),
+            Node::Set(_, set) => {
+                collect_expr_variables(&set.value, bound, referenced);
+                bound.insert(set.key.clone());
+            }
+            Node::FilterSection(_, section, _) => {
+                for arg in section.filter.args.values() {
+                    collect_expr_variables(arg, bound, referenced);
+                }
+                collect_variables(&section.body, bound, referenced);
+            }
+            Node::Block(_, block, _) => collect_variables(&block.body, bound, referenced),
+            Node::Forloop(_, forloop, _) => {
+                collect_expr_variables(&forloop.container, bound, referenced);
+                bound.extend(forloop.key.iter().cloned());
+                bound.insert(forloop.value.clone());
+                bound.insert("loop".to_string());
+                collect_variables(&forloop.body, bound, referenced);
+                if let Some(empty_body) = &forloop.empty_body {
+                    collect_variables(empty_body, bound, referenced);
+                }
+            }
+            Node::If(if_node, _) => {
+                for (_, condition, body) in &if_node.conditions {
+                    collect_expr_variables(condition, bound, referenced);
+                    collect_variables(body, bound, referenced);
+                }
+                if let Some((_, body)) = &if_node.otherwise {
+                    collect_variables(body, bound, referenced);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_expr_variables(expr: &Expr, bound: &HashSet<String>, referenced: &mut BTreeSet<String>) {
+    collect_value_variables(&expr.val, bound, referenced);
+    for filter in &expr.filters {
+        for arg in filter.args.values() {
+            collect_expr_variables(arg, bound, referenced);
+        }
+    }
+}
+
+fn collect_value_variables(
+    value: &ExprVal,
+    bound: &HashSet<String>,
+    referenced: &mut BTreeSet<String>,
+) {
+    let mut add_ident = |ident: &str| {
+        // `a.b` and `a[0]` reference `a`
+        let name = ident.split(['.', '[']).next().unwrap_or(ident);
+        if !bound.contains(name) {
+            referenced.insert(name.to_string());
+        }
+    };
+    match value {
+        ExprVal::Ident(ident) => add_ident(ident),
+        ExprVal::Test(test) => {
+            add_ident(&test.ident);
+            for arg in &test.args {
+                collect_expr_variables(arg, bound, referenced);
+            }
+        }
+        ExprVal::Math(math) => {
+            collect_expr_variables(&math.lhs, bound, referenced);
+            collect_expr_variables(&math.rhs, bound, referenced);
+        }
+        ExprVal::Logic(logic) => {
+            collect_expr_variables(&logic.lhs, bound, referenced);
+            collect_expr_variables(&logic.rhs, bound, referenced);
+        }
+        ExprVal::In(in_expr) => {
+            collect_expr_variables(&in_expr.lhs, bound, referenced);
+            collect_expr_variables(&in_expr.rhs, bound, referenced);
+        }
+        ExprVal::FunctionCall(call) => {
+            for arg in call.args.values() {
+                collect_expr_variables(arg, bound, referenced);
+            }
+        }
+        ExprVal::MacroCall(call) => {
+            for arg in call.args.values() {
+                collect_expr_variables(arg, bound, referenced);
+            }
+        }
+        ExprVal::Array(values) => {
+            for value in values {
+                collect_expr_variables(value, bound, referenced);
+            }
+        }
+        ExprVal::StringConcat(concat) => {
+            for value in &concat.values {
+                collect_value_variables(value, bound, referenced);
+            }
+        }
+        ExprVal::String(_) | ExprVal::Int(_) | ExprVal::Float(_) | ExprVal::Bool(_) => {}
+    }
+}
+
 pub static PROMPT_TO_CONVENTIONAL_COMMIT_PREFIX: &str =
     include_str!("../prompts/conventional_commit.tera");
-pub static PROMPT_TO_SUMMARIZE_DIFF: &str = include_str!("../prompts/summarize_file_diff.tera");
-pub static PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES: &str =
-    include_str!("../prompts/summarize_commit.tera");
+pub static PROMPT_TO_CONVENTIONAL_COMMIT_SCOPE: &str =
+    include_str!("../prompts/conventional_commit_scope.tera");
+pub static PROMPT_TO_SUMMARIZE_DIFF_BATCH: &str =
+    include_str!("../prompts/summarize_file_diffs_batch.tera");
+pub static PROMPT_TO_SUMMARIZE_NEW_FILE: &str = include_str!("../prompts/summarize_new_file.tera");
 pub static PROMPT_TO_SUMMARIZE_DIFF_TITLE: &str = include_str!("../prompts/title_commit.tera");
 pub static PROMPT_TO_TRANSLATE: &str = include_str!("../prompts/translation.tera");
+
+/// The parts the built-in prompts share, by the name they are included with.
+static PARTIALS: &[(&str, &str)] = &[
+    (
+        "git_diff_format.tera",
+        include_str!("../prompts/partials/git_diff_format.tera"),
+    ),
+    (
+        "file_diff_instructions.tera",
+        include_str!("../prompts/partials/file_diff_instructions.tera"),
+    ),
+    (
+        "change_type.tera",
+        include_str!("../prompts/partials/change_type.tera"),
+    ),
+    (
+        "commit_summary_intro.tera",
+        include_str!("../prompts/partials/commit_summary_intro.tera"),
+    ),
+    (
+        "commit_summary_context.tera",
+        include_str!("../prompts/partials/commit_summary_context.tera"),
+    ),
+];
+
+lazy_static! {
+    static ref INCLUDE_REGEX: Regex = Regex::new(r#"\{%-?\s*include\s+"([^"]+)"\s*-?%\}"#).unwrap();
+    pub static ref PROMPT_TO_SUMMARIZE_DIFF: String =
+        expand_includes(include_str!("../prompts/summarize_file_diff.tera"));
+    pub static ref PROMPT_TO_SUMMARIZE_API_DIFF: String =
+        expand_includes(include_str!("../prompts/summarize_api_file_diff.tera"));
+    pub static ref PROMPT_TO_SUMMARIZE_TEST_DIFF: String =
+        expand_includes(include_str!("../prompts/summarize_test_file_diff.tera"));
+    pub static ref PROMPT_TO_SUMMARIZE_CONFIG_DIFF: String =
+        expand_includes(include_str!("../prompts/summarize_config_file_diff.tera"));
+    pub static ref PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES: String =
+        expand_includes(include_str!("../prompts/summarize_commit.tera"));
+    pub static ref PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES_PROSE: String =
+        expand_includes(include_str!("../prompts/summarize_commit_prose.tera"));
+    pub static ref PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES_WHAT_WHY: String =
+        expand_includes(include_str!("../prompts/summarize_commit_what_why.tera"));
+}
+
+fn partial(name: &str) -> &'static str {
+    PARTIALS
+        .iter()
+        .find(|(partial_name, _)| *partial_name == name)
+        .map(|(_, partial)| *partial)
+        .unwrap_or_else(|| panic!("no partial named {name}"))
+}
+
+/// What the per-file prompts say about a file that is added, deleted or
+/// renamed, for the labeled diffs of a batch. Empty for a modified file.
+pub(crate) fn change_type_note(change_type: &str) -> Result<String, Error> {
+    let note = format_prompt(
+        partial("change_type.tera"),
+        HashMap::from([("change_type", change_type)]),
+    )?;
+    Ok(note.trim().to_string())
+}
+
+/// Replaces the `{% include "<name>" %}` tags of a built-in prompt with the
+/// [`PARTIALS`] they name. The prompt stays a single template, which users can
+/// copy and edit, and whose static lines are still sent as the system prompt.
+fn expand_includes(prompt: &str) -> String {
+    INCLUDE_REGEX
+        .replace_all(prompt, |caps: &Captures| {
+            let partial = partial(&caps[1]);
+            expand_includes(partial.strip_suffix('\n').unwrap_or(partial))
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_prompt(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("gptcommit-{}-{name}", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_prompt_from_file() {
+        let path = write_prompt("title.tera", "Title for:\n{{ summary_points }}\n");
+
+        let prompt = load_prompt(
+            "commit_title",
+            format!("file:{}", path.display()),
+            &["summary_points"],
+        )
+        .unwrap();
+
+        assert_eq!(prompt, "Title for:\n{{ summary_points }}\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_prompt_errors_name_the_stage() {
+        let err =
+            load_prompt("file_diff", "file:./does/not/exist.tera".to_string(), &[]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Could not read the prompt.file_diff file"));
+
+        let path = write_prompt("broken.tera", "{% if summary_points %}unclosed");
+        let err =
+            load_prompt("commit_summary", format!("file:{}", path.display()), &[]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("prompt.commit_summary is not a valid template"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_preamble_goes_in_the_system_prompt() {
+        let prompt =
+            render_prompt("Summarize:\n{{ x }}\n", HashMap::from([("x", "diff")])).unwrap();
+        let preamble = "Write tersely, {{ x }}.";
+
+        let with_preamble = prompt.clone().with_preamble(preamble);
+        assert_eq!(
+            with_preamble.system,
+            "Write tersely, {{ x }}.\n\nSummarize:\n"
+        );
+        assert_eq!(with_preamble.user, "diff\n");
+
+        let user_only = Prompt {
+            system: String::new(),
+            user: "diff".to_string(),
+        };
+        assert_eq!(
+            user_only.with_preamble(preamble).joined(),
+            "Write tersely, {{ x }}.\n\ndiff"
+        );
+        assert_eq!(prompt.clone().with_preamble(""), prompt);
+    }
+
+    #[test]
+    fn test_load_inline_prompt() {
+        assert_eq!(
+            load_prompt(
+                "translation",
+                "Translate {{ commit_message }}".to_string(),
+                &["commit_message", "output_language"]
+            )
+            .unwrap(),
+            "Translate {{ commit_message }}"
+        );
+    }
+
+    #[test]
+    fn test_unknown_variables_are_rejected() {
+        let variables = ["file_diff", "commit_message"];
+        let err = load_prompt(
+            "file_diff",
+            "Summarize {{ file_dif }}".to_string(),
+            &variables,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "prompt.file_diff references the unknown variable `file_dif`. Available variables: file_diff, commit_message"
+        );
+
+        let err = load_prompt(
+            "file_diff",
+            "{% if commit_mesage %}{{ file_diff }}{% endif %}".to_string(),
+            &variables,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("`commit_mesage`"));
+    }
+
+    #[test]
+    fn test_well_formed_prompt_passes() {
+        let prompt = "{% set diff = file_diff | trim %}{% for line in diff | split(pat=\"\\n\") %}{{ loop.index }} {{ line }}{% endfor %}{% if commit_message %}{{ commit_message | upper }}{% endif %}";
+
+        assert!(load_prompt(
+            "file_diff",
+            prompt.to_string(),
+            &["file_diff", "commit_message"]
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_render_prompt_splits_off_the_instructions() {
+        let prompt = render_prompt(
+            PROMPT_TO_SUMMARIZE_DIFF_TITLE,
+            HashMap::from([("summary_points", "- Add a widget"), ("commit_message", "")]),
+        )
+        .unwrap();
+        assert!(prompt
+            .system
+            .starts_with("You are an expert programmer writing a commit message title."));
+        assert!(!prompt.system.contains("Add a widget"));
+        assert!(prompt
+            .user
+            .contains("THE FILE SUMMARIES:\n```\n- Add a widget\n```"));
+        assert_eq!(
+            prompt.joined(),
+            format_prompt(
+                PROMPT_TO_SUMMARIZE_DIFF_TITLE,
+                HashMap::from([("summary_points", "- Add a widget"), ("commit_message", "")]),
+            )
+            .unwrap()
+        );
+
+        // the branch is only mentioned when one is given
+        assert!(!prompt.user.contains("BRANCH"));
+        let prompt = render_prompt(
+            PROMPT_TO_SUMMARIZE_DIFF_TITLE,
+            HashMap::from([
+                ("summary_points", "- Add a widget"),
+                ("commit_message", ""),
+                ("branch", "feature/widget"),
+            ]),
+        )
+        .unwrap();
+        assert!(prompt.user.contains("THE BRANCH `feature/widget`"));
+
+        let prompt = render_prompt("Say hi", HashMap::new()).unwrap();
+        assert_eq!(prompt.system, "");
+        assert_eq!(prompt.user, "Say hi");
+    }
+
+    #[test]
+    fn test_builtin_prompts_expand_their_includes() {
+        let instructions = expand_includes(partial("file_diff_instructions.tera"));
+        for prompt in [&*PROMPT_TO_SUMMARIZE_DIFF, &*PROMPT_TO_SUMMARIZE_API_DIFF] {
+            assert!(!prompt.contains("{% include"));
+            assert!(prompt.contains("Reminders about the git diff format:"));
+            assert!(prompt.ends_with(&instructions));
+        }
+        // the commit summary prompts differ only in the style they ask for
+        let context = expand_includes(partial("commit_summary_context.tera"));
+        for prompt in [
+            &*PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES,
+            &*PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES_PROSE,
+            &*PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES_WHAT_WHY,
+        ] {
+            assert!(prompt.starts_with(partial("commit_summary_intro.tera")));
+            assert!(prompt.contains(&context));
+        }
+        // every per-file prompt says how the file changes
+        for prompt in [
+            &*PROMPT_TO_SUMMARIZE_DIFF,
+            &*PROMPT_TO_SUMMARIZE_API_DIFF,
+            &*PROMPT_TO_SUMMARIZE_TEST_DIFF,
+            &*PROMPT_TO_SUMMARIZE_CONFIG_DIFF,
+        ] {
+            let prompt = format_prompt(
+                prompt,
+                HashMap::from([("file_diff", ""), ("change_type", "deleted")]),
+            )
+            .unwrap();
+            assert!(prompt.contains("This file is deleted in this commit."));
+        }
+        assert_eq!(change_type_note("modified").unwrap(), "");
+        assert_eq!(
+            change_type_note("renamed").unwrap(),
+            "This file is renamed in this commit. Mention the rename along with any changes to its content."
+        );
+        // the included lines are static, so they stay in the system prompt
+        let prompt = render_prompt(
+            &PROMPT_TO_SUMMARIZE_API_DIFF,
+            HashMap::from([("file_diff", "diff --git a/lib.rs b/lib.rs\n")]),
+        )
+        .unwrap();
+        assert!(prompt
+            .system
+            .contains("Reminders about the git diff format:"));
+        assert!(prompt
+            .system
+            .contains("Start every comment about a public API change"));
+    }
+
+    #[test]
+    fn test_builtin_prompts_only_use_provided_variables() {
+        let title = [
+            "summary_points",
+            "commit_message",
+            "intent",
+            "branch",
+            "recent_commits",
+            "files_changed",
+            "insertions",
+            "deletions",
+        ];
+        let commit_summary = [
+            "summary_points",
+            "commit_message",
+            "intent",
+            "branch",
+            "max_summary_bullets",
+            "files_changed",
+            "insertions",
+            "deletions",
+        ];
+        let file_diff = [
+            "file_diff",
+            "commit_message",
+            "max_file_bullets",
+            "change_type",
+        ];
+        for (stage, prompt, variables) in [
+            (
+                "file_diff",
+                PROMPT_TO_SUMMARIZE_DIFF.as_str(),
+                &file_diff[..],
+            ),
+            (
+                "file_diffs_batch",
+                PROMPT_TO_SUMMARIZE_DIFF_BATCH,
+                &["file_diffs", "commit_message", "max_file_bullets"],
+            ),
+            (
+                "test_file_diff",
+                PROMPT_TO_SUMMARIZE_TEST_DIFF.as_str(),
+                &file_diff,
+            ),
+            (
+                "config_file_diff",
+                PROMPT_TO_SUMMARIZE_CONFIG_DIFF.as_str(),
+                &file_diff,
+            ),
+            (
+                "api_file_diff",
+                PROMPT_TO_SUMMARIZE_API_DIFF.as_str(),
+                &file_diff,
+            ),
+            (
+                "new_file",
+                PROMPT_TO_SUMMARIZE_NEW_FILE,
+                &["file_outline", "commit_message", "max_file_bullets"],
+            ),
+            (
+                "commit_summary",
+                PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES.as_str(),
+                &commit_summary,
+            ),
+            (
+                "commit_summary",
+                PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES_PROSE.as_str(),
+                &commit_summary,
+            ),
+            (
+                "commit_summary",
+                PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES_WHAT_WHY.as_str(),
+                &commit_summary,
+            ),
+            ("commit_title", PROMPT_TO_SUMMARIZE_DIFF_TITLE, &title),
+            (
+                "conventional_commit_prefix",
+                PROMPT_TO_CONVENTIONAL_COMMIT_PREFIX,
+                &["summary_points", "examples", "commit_types"],
+            ),
+            (
+                "conventional_commit_scope",
+                PROMPT_TO_CONVENTIONAL_COMMIT_SCOPE,
+                &["summary_points"],
+            ),
+            (
+                "translation",
+                PROMPT_TO_TRANSLATE,
+                &["commit_message", "output_language"],
+            ),
+        ] {
+            load_prompt(stage, prompt.to_string(), variables).unwrap();
+        }
+    }
+}
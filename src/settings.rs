@@ -6,24 +6,91 @@ use async_openai::config::OPENAI_API_BASE;
 use config::{
     builder::DefaultState, Config, ConfigBuilder, ConfigError, Environment, File, Source,
 };
+use lazy_static::lazy_static;
+use regex::Regex;
 use serde::Serialize;
 use serde_derive::Deserialize;
 use strum_macros::EnumString;
 
 // You need to bring the ToString trait into scope to use it
 use std::string::ToString;
-use strum_macros::{Display, IntoStaticStr};
+use strum_macros::Display;
 
 use crate::{
     git::get_hooks_path,
     prompt::{
-        PROMPT_TO_CONVENTIONAL_COMMIT_PREFIX, PROMPT_TO_SUMMARIZE_DIFF,
-        PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES, PROMPT_TO_SUMMARIZE_DIFF_TITLE, PROMPT_TO_TRANSLATE,
+        PROMPT_TO_CONVENTIONAL_COMMIT_PREFIX, PROMPT_TO_CONVENTIONAL_COMMIT_SCOPE,
+        PROMPT_TO_SUMMARIZE_API_DIFF, PROMPT_TO_SUMMARIZE_CONFIG_DIFF, PROMPT_TO_SUMMARIZE_DIFF,
+        PROMPT_TO_SUMMARIZE_DIFF_BATCH, PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES,
+        PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES_PROSE, PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES_WHAT_WHY,
+        PROMPT_TO_SUMMARIZE_DIFF_TITLE, PROMPT_TO_SUMMARIZE_NEW_FILE,
+        PROMPT_TO_SUMMARIZE_TEST_DIFF, PROMPT_TO_TRANSLATE,
     },
+    util,
 };
 
 static DEFAULT_OPENAI_MODEL: &str = "gpt-3.5-turbo-16k";
 
+pub(crate) static OPENROUTER_API_BASE: &str = "https://openrouter.ai/api/v1";
+static DEFAULT_OPENROUTER_MODEL: &str = "openai/gpt-3.5-turbo-16k";
+
+static DEFAULT_CHUNK_TOKEN_THRESHOLD: u32 = 4000;
+
+static DEFAULT_NEW_FILE_TOKEN_THRESHOLD: u32 = 2000;
+
+static DEFAULT_MAX_FILES: u32 = 0;
+
+static DEFAULT_PER_FILE_FORMAT: &str = "[{{ file_name }}]\n{{ summary }}\n";
+
+static DEFAULT_MAX_FILES_BEFORE_OVERVIEW: u32 = 300;
+
+static DEFAULT_MAX_SUMMARY_BULLETS: u32 = 5;
+
+static DEFAULT_MAX_FILE_BULLETS: u32 = 5;
+
+static DEFAULT_DOCS_DOMINANT_RATIO: f64 = 0.8;
+
+static DEFAULT_SUMMARY_POINTS_CONTEXT_RATIO: f64 = 0.5;
+
+static DEFAULT_TEST_FILE_PATTERNS: &[&str] = &[
+    "*_test.*",
+    "*.test.*",
+    "*.spec.*",
+    "test_*",
+    "tests/**",
+    "**/tests/**",
+    "test/**",
+    "**/test/**",
+    "__tests__/**",
+    "**/__tests__/**",
+];
+
+static DEFAULT_CI_PATH_PATTERNS: &[&str] = &[
+    ".github/workflows/**",
+    ".github/actions/**",
+    ".gitlab-ci.yml",
+    ".circleci/**",
+    ".travis.yml",
+    "azure-pipelines.yml",
+    "Jenkinsfile",
+];
+
+static DEFAULT_CONFIG_FILE_PATTERNS: &[&str] = &[
+    "*.toml", "*.yaml", "*.yml", "*.json", "*.ini", "*.cfg", "*.conf", "*.env", ".*",
+];
+
+static DEFAULT_UNWRAP_TAGS: &[&str] = &["answer", "commit_message", "summary", "title"];
+
+static DEFAULT_ALLOWED_COMMIT_TYPES: &[&str] = &[
+    "build", "chore", "ci", "docs", "feat", "fix", "perf", "refactor", "style", "test", "wip",
+];
+
+static DEFAULT_STRIP_PREAMBLES: &[&str] = &["Sure", "Certainly", "Of course", "Here is", "Here's"];
+
+static DEFAULT_SKIP_COMMIT_SOURCES: &[&str] = &["template", "merge", "squash", "fixup", "revert"];
+
+static DEFAULT_BRANCH_TICKET_REGEX: &str = r"[A-Z]+-\d+";
+
 static DEFAULT_FILES_TO_IGNORE: &[&str; 8] = &[
     "bun.lockb",
     "Cargo.lock",
@@ -44,6 +111,12 @@ pub(crate) enum ModelProvider {
     #[strum(serialize = "tester-foobar")]
     #[serde(rename = "tester-foobar")]
     TesterFoobar,
+    #[strum(serialize = "mock")]
+    #[serde(rename = "mock")]
+    Mock,
+    #[strum(serialize = "openrouter")]
+    #[serde(rename = "openrouter")]
+    OpenRouter,
 }
 
 // implement the trait `From<ModelProvider>` for `ValueKind`
@@ -115,13 +188,75 @@ impl From<OpenAISettings> for config::ValueKind {
     }
 }
 
+#[derive(Default, Serialize, Deserialize, Clone)]
+pub(crate) struct OpenRouterSettings {
+    pub api_base: Option<String>,
+    pub api_key: Option<String>,
+    /// Model to route to, e.g. `anthropic/claude-3.5-sonnet`
+    pub model: Option<String>,
+    /// App URL sent as `HTTP-Referer` for OpenRouter's app attribution
+    pub referer: Option<String>,
+    /// App name sent as `X-Title` for OpenRouter's app attribution
+    pub title: Option<String>,
+}
+
+impl std::fmt::Debug for OpenRouterSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpenRouterSettings")
+            .field("api_base", &self.api_base)
+            .field(
+                "api_key",
+                // obfuscate the api key
+                &self.api_key.as_ref().map(|_| "********"),
+            )
+            .field("model", &self.model)
+            .field("referer", &self.referer)
+            .field("title", &self.title)
+            .finish()
+    }
+}
+
+// implement the trait `From<OpenRouterSettings>` for `ValueKind`
+impl From<OpenRouterSettings> for config::ValueKind {
+    fn from(settings: OpenRouterSettings) -> Self {
+        let mut properties = HashMap::new();
+        properties.insert(
+            "api_base".to_string(),
+            config::Value::from(settings.api_base),
+        );
+        properties.insert("api_key".to_string(), config::Value::from(settings.api_key));
+        properties.insert("model".to_string(), config::Value::from(settings.model));
+        properties.insert("referer".to_string(), config::Value::from(settings.referer));
+        properties.insert("title".to_string(), config::Value::from(settings.title));
+        Self::Table(properties)
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub(crate) struct PromptSettings {
+    /// Text put before every rendered prompt, e.g. the voice of a team, in the system message
+    pub preamble: Option<String>,
     pub conventional_commit_prefix: Option<String>,
+    /// Earlier changes and their labels for the conventional commit prefix prompt, like `"refactor: Move the parser into its own module"`
+    pub conventional_commit_examples: Option<Vec<String>>,
+    /// Prompt for the scope when `output.require_scope` is set and no scope was detected
+    pub conventional_commit_scope: Option<String>,
     pub commit_summary: Option<String>,
     pub commit_title: Option<String>,
     pub file_diff: Option<String>,
+    /// Prompt for several labeled file diffs at once, when over `output.max_files`
+    pub file_diffs_batch: Option<String>,
+    /// Prompt for diffs of files matching `output.test_file_patterns`
+    pub test_file_diff: Option<String>,
+    /// Prompt for diffs of files matching `output.config_file_patterns`
+    pub config_file_diff: Option<String>,
+    /// Prompt for the other file diffs when `output.highlight_api_changes` is set
+    pub api_file_diff: Option<String>,
+    /// Prompt for the outline of newly added files above `output.new_file_token_threshold`
+    pub new_file: Option<String>,
     pub translation: Option<String>,
+    /// Translation prompts for particular output languages, keyed by language code, e.g. `ja`
+    pub translation_overrides: Option<HashMap<String, String>>,
 }
 
 // implement the trait `From<PromptSettings>` for `ValueKind`
@@ -129,10 +264,22 @@ impl From<PromptSettings> for config::ValueKind {
     fn from(settings: PromptSettings) -> Self {
         let mut properties = HashMap::new();
 
+        properties.insert(
+            "preamble".to_string(),
+            config::Value::from(settings.preamble),
+        );
         properties.insert(
             "conventional_commit_prefix".to_string(),
             config::Value::from(settings.conventional_commit_prefix),
         );
+        properties.insert(
+            "conventional_commit_examples".to_string(),
+            config::Value::from(settings.conventional_commit_examples),
+        );
+        properties.insert(
+            "conventional_commit_scope".to_string(),
+            config::Value::from(settings.conventional_commit_scope),
+        );
         properties.insert(
             "commit_summary".to_string(),
             config::Value::from(settings.commit_summary),
@@ -145,30 +292,385 @@ impl From<PromptSettings> for config::ValueKind {
             "file_diff".to_string(),
             config::Value::from(settings.file_diff),
         );
+        properties.insert(
+            "file_diffs_batch".to_string(),
+            config::Value::from(settings.file_diffs_batch),
+        );
+        properties.insert(
+            "test_file_diff".to_string(),
+            config::Value::from(settings.test_file_diff),
+        );
+        properties.insert(
+            "config_file_diff".to_string(),
+            config::Value::from(settings.config_file_diff),
+        );
+        properties.insert(
+            "api_file_diff".to_string(),
+            config::Value::from(settings.api_file_diff),
+        );
+        properties.insert(
+            "new_file".to_string(),
+            config::Value::from(settings.new_file),
+        );
         properties.insert(
             "translation".to_string(),
             config::Value::from(settings.translation),
         );
+        properties.insert(
+            "translation_overrides".to_string(),
+            config::Value::from(settings.translation_overrides),
+        );
         Self::Table(properties)
     }
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Display, EnumString, IntoStaticStr)]
-#[strum(serialize_all = "kebab-case")]
+/// Output language of the commit message. Languages without a dedicated
+/// variant are kept as their BCP-47 tag, e.g. `pt-BR` or `uk`.
+#[derive(Debug, Default, Clone, PartialEq)]
 pub enum Language {
     #[default]
-    #[strum(serialize = "en")]
-    #[strum(to_string = "English")]
     En,
-    #[strum(serialize = "zh-cn")]
-    #[strum(to_string = "Simplified Chinese")]
     ZhCn,
-    #[strum(serialize = "zh-tw")]
-    #[strum(to_string = "Traditional Chinese")]
     ZhTw,
-    #[strum(serialize = "ja")]
-    #[strum(to_string = "Japanese")]
     Ja,
+    Other(String),
+}
+
+lazy_static! {
+    static ref LANGUAGE_TAG_REGEX: Regex =
+        Regex::new(r"^[A-Za-z]{2,3}(-[A-Za-z0-9]{1,8})*$").unwrap();
+}
+
+/// Natural-language names of languages without a dedicated variant, by lowercase tag.
+static LANGUAGE_NAMES: &[(&str, &str)] = &[
+    ("ar", "Arabic"),
+    ("cs", "Czech"),
+    ("de", "German"),
+    ("es", "Spanish"),
+    ("fr", "French"),
+    ("hi", "Hindi"),
+    ("it", "Italian"),
+    ("ko", "Korean"),
+    ("nl", "Dutch"),
+    ("pl", "Polish"),
+    ("pt", "Portuguese"),
+    ("pt-br", "Brazilian Portuguese"),
+    ("ru", "Russian"),
+    ("sv", "Swedish"),
+    ("tr", "Turkish"),
+    ("uk", "Ukrainian"),
+    ("vi", "Vietnamese"),
+];
+
+impl FromStr for Language {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !LANGUAGE_TAG_REGEX.is_match(s) {
+            return Err(format!("Invalid language: {s}."));
+        }
+        let tag = s.to_lowercase();
+        Ok(match tag.as_str() {
+            "zh-cn" | "zh-hans" => Language::ZhCn,
+            "zh-tw" | "zh-hant" => Language::ZhTw,
+            "ja" | "ja-jp" => Language::Ja,
+            _ if tag == "en" || tag.starts_with("en-") => Language::En,
+            _ => Language::Other(s.to_string()),
+        })
+    }
+}
+
+impl std::fmt::Display for Language {
+    /// The name of the language as used in the translation prompt. Unknown
+    /// tags fall back to the name of their primary language, then to the tag itself.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Language::En => "English",
+            Language::ZhCn => "Simplified Chinese",
+            Language::ZhTw => "Traditional Chinese",
+            Language::Ja => "Japanese",
+            Language::Other(tag) => {
+                let tag = tag.to_lowercase();
+                let primary = tag.split('-').next().unwrap_or_default();
+                return match [tag.as_str(), primary]
+                    .iter()
+                    .find_map(|t| LANGUAGE_NAMES.iter().find(|(k, _)| k == t))
+                {
+                    Some((_, name)) => write!(f, "{name}"),
+                    None => write!(f, "{}", self.tag()),
+                };
+            }
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl Language {
+    /// The language tag, as written in `output.lang`.
+    pub fn tag(&self) -> &str {
+        match self {
+            Language::En => "en",
+            Language::ZhCn => "zh-cn",
+            Language::ZhTw => "zh-tw",
+            Language::Ja => "ja",
+            Language::Other(tag) => tag,
+        }
+    }
+
+    /// Cheaply checks, by Unicode script, whether the text already appears to be
+    /// written in this language. Only conclusive for languages with a distinct script.
+    pub fn is_likely_written_in(&self, text: &str) -> bool {
+        let (mut letters, mut kana, mut han) = (0usize, 0usize, 0usize);
+        for c in text.chars().filter(|c| c.is_alphabetic()) {
+            letters += 1;
+            match c {
+                '\u{3040}'..='\u{30ff}' | '\u{31f0}'..='\u{31ff}' => kana += 1,
+                '\u{4e00}'..='\u{9fff}' | '\u{3400}'..='\u{4dbf}' => han += 1,
+                _ => {}
+            }
+        }
+        if letters == 0 {
+            return false;
+        }
+        let is_mostly = |count: usize| count * 2 >= letters;
+        match self {
+            Language::En => !is_mostly(kana + han),
+            Language::Ja => kana > 0 && is_mostly(kana + han),
+            Language::ZhCn | Language::ZhTw => kana == 0 && is_mostly(han),
+            Language::Other(_) => false,
+        }
+    }
+}
+
+/// How the conventional commit prefix is chosen
+#[derive(Debug, Default, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum PrefixStrategy {
+    /// Always ask the LLM, except for commits changing only CI paths
+    #[default]
+    Llm,
+    /// Only use the path-based heuristics, no prefix when they are inconclusive
+    Heuristic,
+    /// Use the path-based heuristics, asking the LLM when they are inconclusive
+    HeuristicThenLlm,
+}
+
+/// How the English message and its translation are combined
+#[derive(Debug, Default, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum BilingualLayout {
+    /// Only the translated message
+    #[default]
+    Off,
+    /// The English message followed by the translated message
+    Stacked,
+    /// The English title followed by the translated body
+    EnglishTitle,
+}
+
+/// What to do when a generated title or summary matches `output.forbidden_patterns`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum ForbiddenPatternsAction {
+    /// Remove the matches
+    #[default]
+    Sanitize,
+    /// Ask the model once more, then remove any matches left
+    Reprompt,
+}
+
+/// What to do when the generated body only restates the title
+#[derive(Debug, Default, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum RedundantBodyAction {
+    /// Keep the body as is
+    #[default]
+    Keep,
+    /// Drop the body, leaving a title-only message
+    Drop,
+    /// Ask the model once more, then drop the body if it is still redundant
+    Reprompt,
+}
+
+/// What the prepare-commit-msg hook does when generating the message fails
+#[derive(Debug, Default, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum HookErrorAction {
+    /// Fail the hook, aborting the commit
+    #[default]
+    Abort,
+    /// Leave the commit message as git wrote it and let the commit go on
+    Skip,
+    /// Write an empty message, keeping git's comments, for the editor to fill
+    Empty,
+}
+
+/// What to do when the per-file summaries reach `limits.max_total_tokens`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum BudgetAction {
+    /// Fail without summarizing the commit
+    #[default]
+    Error,
+    /// Summarize the commit from the file summaries completed so far
+    Partial,
+}
+
+/// How the commit message file is encoded
+#[derive(Debug, Default, Clone, Copy, PartialEq, Display, EnumString)]
+pub enum OutputEncoding {
+    /// Plain UTF-8
+    #[default]
+    #[strum(serialize = "utf-8")]
+    Utf8,
+    /// UTF-8 starting with a byte order mark, for tools that require one
+    #[strum(serialize = "utf-8-bom")]
+    Utf8Bom,
+}
+
+impl OutputEncoding {
+    /// The bytes written for the message.
+    pub(crate) fn encode(self, message: &str) -> Vec<u8> {
+        match self {
+            Self::Utf8 => message.as_bytes().to_vec(),
+            Self::Utf8Bom => ["\u{feff}", message].concat().into_bytes(),
+        }
+    }
+}
+
+/// The casing of the title after the conventional commit prefix
+#[derive(Debug, Default, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum TitleCase {
+    /// Keep the casing of the model
+    #[default]
+    AsIs,
+    /// Lowercase the first letter, e.g. `feat: add a widget`
+    LowerFirst,
+    /// Uppercase the first letter, e.g. `feat: Add a widget`
+    Sentence,
+}
+
+impl TitleCase {
+    /// Applies the casing to the first letter of the title. A first word with
+    /// other capitals, like `README` or `OpenAI`, is not lowercased.
+    pub(crate) fn apply(self, title: &str) -> String {
+        let mut chars = title.chars();
+        let Some(first) = chars.next() else {
+            return title.to_string();
+        };
+        let rest = chars.as_str();
+        match self {
+            Self::AsIs => title.to_string(),
+            Self::LowerFirst
+                if rest
+                    .split(char::is_whitespace)
+                    .next()
+                    .is_some_and(|word| word.chars().any(char::is_uppercase)) =>
+            {
+                title.to_string()
+            }
+            Self::LowerFirst => first.to_lowercase().chain(rest.chars()).collect(),
+            Self::Sentence => first.to_uppercase().chain(rest.chars()).collect(),
+        }
+    }
+}
+
+/// The shape of the body written by the built-in `prompt.commit_summary`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum BodyStyle {
+    /// A few bullet points
+    #[default]
+    Bullets,
+    /// One or two short paragraphs
+    Prose,
+    /// A `What:` and a `Why:` section
+    WhatWhy,
+}
+
+impl BodyStyle {
+    /// The built-in summary prompt writing bodies of this style.
+    pub(crate) fn summary_prompt(self) -> &'static str {
+        match self {
+            Self::Bullets => PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES.as_str(),
+            Self::Prose => PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES_PROSE.as_str(),
+            Self::WhatWhy => PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES_WHAT_WHY.as_str(),
+        }
+    }
+}
+
+/// A built-in transform of the completions of a stage, listed in `postprocess.<stage>`
+#[derive(Debug, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum Postprocessor {
+    /// Remove a final period, as `output.title_strip_period` does for titles
+    StripTrailingPeriod,
+    /// Turn runs of spaces and tabs within each line into a single space
+    CollapseWhitespace,
+    /// Remove emoji, leaving the whitespace around them
+    StripEmoji,
+    /// Lowercase the first letter, unless the first word has other capitals
+    LowercaseFirst,
+}
+
+impl Postprocessor {
+    pub(crate) fn apply(self, completion: &str) -> String {
+        match self {
+            Self::StripTrailingPeriod => util::strip_trailing_period(completion),
+            Self::CollapseWhitespace => util::collapse_whitespace(completion),
+            Self::StripEmoji => util::strip_emoji(completion),
+            Self::LowercaseFirst => TitleCase::LowerFirst.apply(completion),
+        }
+    }
+}
+
+/// Whether and how provenance metadata is appended to the commit message
+#[derive(Debug, Default, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum MetadataComment {
+    /// No metadata
+    #[default]
+    Off,
+    /// A `# gptcommit: {...}` line, removed by git when it cleans up the message
+    Git,
+    /// A `<!-- gptcommit: {...} -->` line, kept in the commit
+    Html,
+}
+
+/// Accepts either a string or a list of strings, joining lists with commas.
+fn deserialize_string_or_list<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct StringOrListVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for StringOrListVisitor {
+        type Value = Option<String>;
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(formatter, "a string or a list of strings")
+        }
+        fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Self::Value, E> {
+            Ok(Some(s.to_string()))
+        }
+        fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+        fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(
+            self,
+            mut seq: A,
+        ) -> Result<Self::Value, A::Error> {
+            let mut items: Vec<String> = vec![];
+            while let Some(item) = seq.next_element::<String>()? {
+                items.push(item);
+            }
+            Ok(Some(items.join(",")))
+        }
+    }
+    deserializer.deserialize_any(StringOrListVisitor)
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -177,10 +679,107 @@ pub struct OutputSettings {
     pub conventional_commit: Option<bool>,
     /// The format of the conventional commit tag
     pub conventional_commit_prefix_format: Option<String>,
-    /// Output language of the commit message
+    /// How to choose the conventional commit tag: "llm", "heuristic" or "heuristic_then_llm"
+    pub prefix_strategy: Option<String>,
+    /// Whether every conventional commit tag must have a scope, as in `feat(parser): `
+    pub require_scope: Option<bool>,
+    /// The conventional commit types the prefix may be classified as, e.g. with `release` added
+    pub allowed_commit_types: Option<Vec<String>>,
+    /// Output language of the commit message, or a list of languages. `auto` detects it from the comments and strings of the diffs
+    #[serde(default, deserialize_with = "deserialize_string_or_list")]
     pub lang: Option<String>,
+    /// Separator between the translations when multiple languages are configured
+    pub multilang_separator: Option<String>,
+    /// Whether to keep the English message next to the translation: "off", "stacked" or "english_title"
+    pub bilingual: Option<String>,
     /// Whether to show the summary of each file in the commit
     pub show_per_file_summary: Option<bool>,
+    /// Text put before the summaries of each file, e.g. a separator line
+    pub per_file_header: Option<String>,
+    /// Template of the summary of each file, with the `file_name` and `summary` variables
+    pub per_file_format: Option<String>,
+    /// The number of files whose summary is shown, those with the longest summaries, 0 for no limit
+    pub per_file_summary_limit: Option<u32>,
+    /// Whether to attach the summary of every file to the commit as a git note under `refs/notes/gptcommit`
+    pub store_per_file_in_git_notes: Option<bool>,
+    /// Whether to generate a title line, or only the summary body with the prefix on its first line
+    pub include_title: Option<bool>,
+    /// Whether to remove a trailing period from the title
+    pub title_strip_period: Option<bool>,
+    /// Whether to ask again, once, for a title whose first word isn't in the imperative mood, like `Added`
+    pub enforce_imperative: Option<bool>,
+    /// The casing of the title after the conventional commit prefix: "as_is", "lower_first" or "sentence"
+    pub title_case: Option<String>,
+    /// The shape of the body when `prompt.commit_summary` is the built-in one: "bullets", "prose" or "what_why"
+    pub body_style: Option<String>,
+    /// What to do when the body only restates the title: "keep", "drop" or "reprompt"
+    pub on_redundant_body: Option<String>,
+    /// The encoding of the written commit message file: "utf-8" or "utf-8-bom"
+    pub encoding: Option<String>,
+    /// Whether to append the model, tokens and version that produced the message as a comment: "off", "git" or "html"
+    pub metadata_comment: Option<String>,
+    /// Whether to append `Change-Type` and `Components` trailers for release notes tooling
+    pub emit_metadata_trailers: Option<bool>,
+    /// Trailer appended last to generated messages, e.g. `Generated-by: gptcommit ({{ model }})`, empty for none
+    pub attribution: Option<String>,
+    /// Whether to convert line endings to LF, strip trailing whitespace and end with one newline
+    pub normalize_whitespace: Option<bool>,
+    /// Whether to write a minimal WIP message when the staged changes look unfinished
+    pub detect_wip: Option<bool>,
+    /// Whether to write a message from the changed files and line counts, instead of failing, when the model provider has no credentials
+    pub offline_fallback: Option<bool>,
+    /// Whether to summarize file diffs with `prompt.api_file_diff` and list the public API changes first in the body
+    pub highlight_api_changes: Option<bool>,
+    /// Whether an existing commit message, e.g. from `git commit -m`, steers the title and summary
+    pub respect_existing_message: Option<bool>,
+    /// Whether to pass the checked out branch name to the title and summary prompts as `{{ branch }}`
+    pub include_branch_context: Option<bool>,
+    /// How many of the latest commit subjects to pass to the title prompt as `{{ recent_commits }}`, 0 for none
+    pub recent_commit_context: Option<u32>,
+    /// Whether a commit template (`commit.template`) that is only comments and placeholders is still passed to the prompts as context
+    pub respect_git_template: Option<bool>,
+    /// Whether to ask again, with a nudge, when the title, summary or prefix completion is empty
+    pub retry_on_empty: Option<bool>,
+    /// Whether to request temperature 0 and a fixed seed for reproducible messages
+    pub deterministic: Option<bool>,
+    /// Files that skip the per-file summary and get a short placeholder instead, as globs
+    pub summarize_as_noop: Option<Vec<String>>,
+    /// Regexes that must not appear in the generated text of the message
+    pub forbidden_patterns: Option<Vec<String>>,
+    /// What to do with forbidden patterns: "sanitize" or "reprompt"
+    pub forbidden_patterns_action: Option<String>,
+    /// Whether to escape code fences and mark instruction-like text in the diffs given to the prompts
+    pub guard_diff_content: Option<bool>,
+    /// Regexes of instruction-like text in the diffs, marked as quoted with `output.guard_diff_content`
+    pub injection_patterns: Option<Vec<String>>,
+    /// Whether to summarize large file diffs in chunks and merge the results
+    pub chunk_large_files: Option<bool>,
+    /// The token count above which a file diff is split into chunks
+    pub chunk_token_threshold: Option<u32>,
+    /// Files summarized with the test file prompt, as globs
+    pub test_file_patterns: Option<Vec<String>>,
+    /// Files summarized with the config file prompt, as globs. Commits changing only these lean toward `chore`
+    pub config_file_patterns: Option<Vec<String>>,
+    /// Commits changing only these paths, as globs, get the `ci` prefix without an LLM call
+    pub ci_path_patterns: Option<Vec<String>>,
+    /// The share of changed lines in documentation files, from 0 to 1, above which a commit that also touches code leans toward `docs`, 0 to disable
+    pub docs_dominant_ratio: Option<f64>,
+    /// The share of the model context, from 0 to 1, the file summaries may take before they are condensed for the final prompts, 0 to disable
+    pub summary_points_context_ratio: Option<f64>,
+    /// The token count above which a newly added file is summarized from its outline, 0 to disable
+    pub new_file_token_threshold: Option<u32>,
+    /// The file count above which small file diffs are summarized in shared batches, 0 to disable
+    pub max_files: Option<u32>,
+    /// The file count above which changes are summarized by directory instead of per file, 0 to disable
+    pub max_files_before_overview: Option<u32>,
+    /// The number of bullet points the commit summary may have, 0 for no limit
+    pub max_summary_bullets: Option<u32>,
+    /// The number of bullet points each file summary may have, 0 for no limit
+    pub max_file_bullets: Option<u32>,
+    /// Whether file diffs changing only whitespace or the file mode are described without an LLM call
+    pub ignore_whitespace_only: Option<bool>,
+    /// The number of unchanged lines kept around the changes of each hunk sent to the model, negative to keep the diff as is
+    pub diff_context_lines: Option<i32>,
 }
 
 // implement the trait `From<OutputSettings>` for `ValueKind`
@@ -195,11 +794,439 @@ impl From<OutputSettings> for config::ValueKind {
             "conventional_commit_prefix_format".to_string(),
             config::Value::from(settings.conventional_commit_prefix_format),
         );
+        properties.insert(
+            "prefix_strategy".to_string(),
+            config::Value::from(settings.prefix_strategy),
+        );
+        properties.insert(
+            "require_scope".to_string(),
+            config::Value::from(settings.require_scope),
+        );
+        properties.insert(
+            "allowed_commit_types".to_string(),
+            config::Value::from(settings.allowed_commit_types),
+        );
         properties.insert("lang".to_string(), config::Value::from(settings.lang));
+        properties.insert(
+            "multilang_separator".to_string(),
+            config::Value::from(settings.multilang_separator),
+        );
+        properties.insert(
+            "bilingual".to_string(),
+            config::Value::from(settings.bilingual),
+        );
         properties.insert(
             "show_per_file_summary".to_string(),
             config::Value::from(settings.show_per_file_summary),
         );
+        properties.insert(
+            "per_file_header".to_string(),
+            config::Value::from(settings.per_file_header),
+        );
+        properties.insert(
+            "per_file_format".to_string(),
+            config::Value::from(settings.per_file_format),
+        );
+        properties.insert(
+            "per_file_summary_limit".to_string(),
+            config::Value::from(settings.per_file_summary_limit),
+        );
+        properties.insert(
+            "store_per_file_in_git_notes".to_string(),
+            config::Value::from(settings.store_per_file_in_git_notes),
+        );
+        properties.insert(
+            "include_title".to_string(),
+            config::Value::from(settings.include_title),
+        );
+        properties.insert(
+            "enforce_imperative".to_string(),
+            config::Value::from(settings.enforce_imperative),
+        );
+        properties.insert(
+            "title_strip_period".to_string(),
+            config::Value::from(settings.title_strip_period),
+        );
+        properties.insert(
+            "title_case".to_string(),
+            config::Value::from(settings.title_case),
+        );
+        properties.insert(
+            "body_style".to_string(),
+            config::Value::from(settings.body_style),
+        );
+        properties.insert(
+            "detect_wip".to_string(),
+            config::Value::from(settings.detect_wip),
+        );
+        properties.insert(
+            "offline_fallback".to_string(),
+            config::Value::from(settings.offline_fallback),
+        );
+        properties.insert(
+            "on_redundant_body".to_string(),
+            config::Value::from(settings.on_redundant_body),
+        );
+        properties.insert(
+            "encoding".to_string(),
+            config::Value::from(settings.encoding),
+        );
+        properties.insert(
+            "metadata_comment".to_string(),
+            config::Value::from(settings.metadata_comment),
+        );
+        properties.insert(
+            "attribution".to_string(),
+            config::Value::from(settings.attribution),
+        );
+        properties.insert(
+            "emit_metadata_trailers".to_string(),
+            config::Value::from(settings.emit_metadata_trailers),
+        );
+        properties.insert(
+            "normalize_whitespace".to_string(),
+            config::Value::from(settings.normalize_whitespace),
+        );
+        properties.insert(
+            "respect_git_template".to_string(),
+            config::Value::from(settings.respect_git_template),
+        );
+        properties.insert(
+            "retry_on_empty".to_string(),
+            config::Value::from(settings.retry_on_empty),
+        );
+        properties.insert(
+            "highlight_api_changes".to_string(),
+            config::Value::from(settings.highlight_api_changes),
+        );
+        properties.insert(
+            "include_branch_context".to_string(),
+            config::Value::from(settings.include_branch_context),
+        );
+        properties.insert(
+            "recent_commit_context".to_string(),
+            config::Value::from(settings.recent_commit_context),
+        );
+        properties.insert(
+            "respect_existing_message".to_string(),
+            config::Value::from(settings.respect_existing_message),
+        );
+        properties.insert(
+            "deterministic".to_string(),
+            config::Value::from(settings.deterministic),
+        );
+        properties.insert(
+            "summarize_as_noop".to_string(),
+            config::Value::from(settings.summarize_as_noop),
+        );
+        properties.insert(
+            "forbidden_patterns".to_string(),
+            config::Value::from(settings.forbidden_patterns),
+        );
+        properties.insert(
+            "forbidden_patterns_action".to_string(),
+            config::Value::from(settings.forbidden_patterns_action),
+        );
+        properties.insert(
+            "guard_diff_content".to_string(),
+            config::Value::from(settings.guard_diff_content),
+        );
+        properties.insert(
+            "injection_patterns".to_string(),
+            config::Value::from(settings.injection_patterns),
+        );
+        properties.insert(
+            "chunk_large_files".to_string(),
+            config::Value::from(settings.chunk_large_files),
+        );
+        properties.insert(
+            "chunk_token_threshold".to_string(),
+            config::Value::from(settings.chunk_token_threshold),
+        );
+        properties.insert(
+            "test_file_patterns".to_string(),
+            config::Value::from(settings.test_file_patterns),
+        );
+        properties.insert(
+            "config_file_patterns".to_string(),
+            config::Value::from(settings.config_file_patterns),
+        );
+        properties.insert(
+            "ci_path_patterns".to_string(),
+            config::Value::from(settings.ci_path_patterns),
+        );
+        properties.insert(
+            "docs_dominant_ratio".to_string(),
+            config::Value::from(settings.docs_dominant_ratio),
+        );
+        properties.insert(
+            "summary_points_context_ratio".to_string(),
+            config::Value::from(settings.summary_points_context_ratio),
+        );
+        properties.insert(
+            "new_file_token_threshold".to_string(),
+            config::Value::from(settings.new_file_token_threshold),
+        );
+        properties.insert(
+            "max_files".to_string(),
+            config::Value::from(settings.max_files),
+        );
+        properties.insert(
+            "max_files_before_overview".to_string(),
+            config::Value::from(settings.max_files_before_overview),
+        );
+        properties.insert(
+            "max_summary_bullets".to_string(),
+            config::Value::from(settings.max_summary_bullets),
+        );
+        properties.insert(
+            "max_file_bullets".to_string(),
+            config::Value::from(settings.max_file_bullets),
+        );
+        properties.insert(
+            "ignore_whitespace_only".to_string(),
+            config::Value::from(settings.ignore_whitespace_only),
+        );
+        properties.insert(
+            "diff_context_lines".to_string(),
+            config::Value::from(settings.diff_context_lines),
+        );
+        Self::Table(properties)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub(crate) struct GitSettings {
+    /// Regex matching ticket ids in the current branch name
+    pub branch_ticket_regex: Option<String>,
+    /// Footer appended when the branch name contains tickets
+    pub branch_ticket_footer: Option<String>,
+    /// Co-authors added as `Co-authored-by` trailers, as `Name <email>`
+    pub co_authors: Option<Vec<String>>,
+    /// Environment variable read for tickets when HEAD is detached, e.g. in CI checkouts
+    pub detached_head_ticket_env: Option<String>,
+    /// Commits left untouched by the hook: the commit sources `message`, `template`,
+    /// `merge`, `squash` and `commit`, plus `fixup` for `fixup!`, `squash!` and
+    /// `amend!` messages and `revert` for `Revert "..."` messages
+    pub skip_commit_sources: Option<Vec<String>>,
+    /// What the hook does when the message can't be generated: `abort`, `skip` or `empty`
+    pub on_error: Option<String>,
+}
+
+// implement the trait `From<GitSettings>` for `ValueKind`
+impl From<GitSettings> for config::ValueKind {
+    fn from(settings: GitSettings) -> Self {
+        let mut properties = HashMap::new();
+        properties.insert(
+            "branch_ticket_regex".to_string(),
+            config::Value::from(settings.branch_ticket_regex),
+        );
+        properties.insert(
+            "branch_ticket_footer".to_string(),
+            config::Value::from(settings.branch_ticket_footer),
+        );
+        properties.insert(
+            "co_authors".to_string(),
+            config::Value::from(settings.co_authors),
+        );
+        properties.insert(
+            "detached_head_ticket_env".to_string(),
+            config::Value::from(settings.detached_head_ticket_env),
+        );
+        properties.insert(
+            "skip_commit_sources".to_string(),
+            config::Value::from(settings.skip_commit_sources),
+        );
+        properties.insert(
+            "on_error".to_string(),
+            config::Value::from(settings.on_error),
+        );
+        Self::Table(properties)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub(crate) struct ModelSettings {
+    /// Whether to remove the prompt from the start of completions, for models that echo it
+    pub strip_echoed_prompt: Option<bool>,
+    /// Tags whose content is extracted when the completion wraps its answer in them, e.g. `answer` for `<answer>...</answer>`
+    pub unwrap_tags: Option<Vec<String>>,
+    /// Whether to keep only the first candidate when the completion offers several, as in `Option 1: ... Option 2: ...`
+    pub pick_first_candidate: Option<bool>,
+    /// Sequences the completion is cut at, sent as the stop sequences of providers supporting them
+    pub stop_sequences: Option<Vec<String>>,
+    /// Starts of a first line introducing the answer to remove, like `Sure` for `Sure, here is the commit message:`
+    pub strip_preambles: Option<Vec<String>>,
+    /// Whether to remove a code fence wrapping the whole completion
+    pub strip_code_fences: Option<bool>,
+    /// Whether to send the static instructions of the prompts as a system message, for providers with message roles
+    pub system_prompt: Option<bool>,
+    /// Whether to ask for the conventional commit type as a JSON object through function calling, for providers supporting it
+    pub structured_prefix: Option<bool>,
+    /// Base URL for embeddings requests, for deployments serving them from another host than the chat completions. Empty to use the provider's `api_base`
+    pub embeddings_base_url: Option<String>,
+}
+
+// implement the trait `From<ModelSettings>` for `ValueKind`
+impl From<ModelSettings> for config::ValueKind {
+    fn from(settings: ModelSettings) -> Self {
+        let mut properties = HashMap::new();
+        properties.insert(
+            "strip_echoed_prompt".to_string(),
+            config::Value::from(settings.strip_echoed_prompt),
+        );
+        properties.insert(
+            "unwrap_tags".to_string(),
+            config::Value::from(settings.unwrap_tags),
+        );
+        properties.insert(
+            "pick_first_candidate".to_string(),
+            config::Value::from(settings.pick_first_candidate),
+        );
+        properties.insert(
+            "stop_sequences".to_string(),
+            config::Value::from(settings.stop_sequences),
+        );
+        properties.insert(
+            "strip_preambles".to_string(),
+            config::Value::from(settings.strip_preambles),
+        );
+        properties.insert(
+            "strip_code_fences".to_string(),
+            config::Value::from(settings.strip_code_fences),
+        );
+        properties.insert(
+            "system_prompt".to_string(),
+            config::Value::from(settings.system_prompt),
+        );
+        properties.insert(
+            "structured_prefix".to_string(),
+            config::Value::from(settings.structured_prefix),
+        );
+        properties.insert(
+            "embeddings_base_url".to_string(),
+            config::Value::from(settings.embeddings_base_url),
+        );
+        Self::Table(properties)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub(crate) struct HttpSettings {
+    /// Whether to slow down requests when the provider's rate limit headers show the budget is nearly spent
+    pub proactive_throttle: Option<bool>,
+}
+
+// implement the trait `From<HttpSettings>` for `ValueKind`
+impl From<HttpSettings> for config::ValueKind {
+    fn from(settings: HttpSettings) -> Self {
+        let mut properties = HashMap::new();
+        properties.insert(
+            "proactive_throttle".to_string(),
+            config::Value::from(settings.proactive_throttle),
+        );
+        Self::Table(properties)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub(crate) struct LimitsSettings {
+    /// The most prompt and completion tokens one run may use, 0 for no limit
+    pub max_total_tokens: Option<u32>,
+    /// What to do when the per-file summaries reach the limit: "error" or "partial"
+    pub on_exceeded: Option<String>,
+    /// The most tokens of a title completion, 0 for no limit
+    pub commit_title_max_tokens: Option<u16>,
+    /// The most tokens of a commit summary completion, 0 for no limit
+    pub commit_summary_max_tokens: Option<u16>,
+    /// The most tokens of a file summary completion, 0 for no limit
+    pub file_diff_max_tokens: Option<u16>,
+    /// The most tokens of a translation completion, 0 for no limit
+    pub translation_max_tokens: Option<u16>,
+}
+
+// implement the trait `From<LimitsSettings>` for `ValueKind`
+impl From<LimitsSettings> for config::ValueKind {
+    fn from(settings: LimitsSettings) -> Self {
+        let mut properties = HashMap::new();
+        properties.insert(
+            "max_total_tokens".to_string(),
+            config::Value::from(settings.max_total_tokens),
+        );
+        properties.insert(
+            "on_exceeded".to_string(),
+            config::Value::from(settings.on_exceeded),
+        );
+        properties.insert(
+            "commit_title_max_tokens".to_string(),
+            config::Value::from(settings.commit_title_max_tokens),
+        );
+        properties.insert(
+            "commit_summary_max_tokens".to_string(),
+            config::Value::from(settings.commit_summary_max_tokens),
+        );
+        properties.insert(
+            "file_diff_max_tokens".to_string(),
+            config::Value::from(settings.file_diff_max_tokens),
+        );
+        properties.insert(
+            "translation_max_tokens".to_string(),
+            config::Value::from(settings.translation_max_tokens),
+        );
+        Self::Table(properties)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub(crate) struct PostprocessSettings {
+    /// Transforms applied in order to the title, e.g. `["strip_emoji", "collapse_whitespace"]`
+    pub commit_title: Option<Vec<String>>,
+    /// Transforms applied in order to the summary of the commit
+    pub commit_summary: Option<Vec<String>>,
+    /// Transforms applied in order to the summary of each file
+    pub file_diff: Option<Vec<String>>,
+    /// Transforms applied in order to the translated message
+    pub translation: Option<Vec<String>>,
+}
+
+// implement the trait `From<PostprocessSettings>` for `ValueKind`
+impl From<PostprocessSettings> for config::ValueKind {
+    fn from(settings: PostprocessSettings) -> Self {
+        let mut properties = HashMap::new();
+        properties.insert(
+            "commit_title".to_string(),
+            config::Value::from(settings.commit_title),
+        );
+        properties.insert(
+            "commit_summary".to_string(),
+            config::Value::from(settings.commit_summary),
+        );
+        properties.insert(
+            "file_diff".to_string(),
+            config::Value::from(settings.file_diff),
+        );
+        properties.insert(
+            "translation".to_string(),
+            config::Value::from(settings.translation),
+        );
+        Self::Table(properties)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub(crate) struct MockSettings {
+    /// Completions returned in order by the mock model provider, repeating the last one
+    pub responses: Option<Vec<String>>,
+}
+
+// implement the trait `From<MockSettings>` for `ValueKind`
+impl From<MockSettings> for config::ValueKind {
+    fn from(settings: MockSettings) -> Self {
+        let mut properties = HashMap::new();
+        properties.insert(
+            "responses".to_string(),
+            config::Value::from(settings.responses),
+        );
         Self::Table(properties)
     }
 }
@@ -208,8 +1235,15 @@ impl From<OutputSettings> for config::ValueKind {
 pub(crate) struct Settings {
     pub model_provider: Option<ModelProvider>,
     pub openai: Option<OpenAISettings>,
+    pub openrouter: Option<OpenRouterSettings>,
+    pub mock: Option<MockSettings>,
+    pub model: Option<ModelSettings>,
+    pub http: Option<HttpSettings>,
+    pub limits: Option<LimitsSettings>,
     pub prompt: Option<PromptSettings>,
     pub output: Option<OutputSettings>,
+    pub git: Option<GitSettings>,
+    pub postprocess: Option<PostprocessSettings>,
     /// Whether to run githook when amending the commit
     pub allow_amend: Option<bool>,
     /// Files to ignore, format similar to gitignore
@@ -224,21 +1258,170 @@ impl Settings {
     }
 
     pub fn from_set_override(key: &str, value: &str) -> Result<Self, ConfigError> {
-        if key == "output.lang" && Language::from_str(value).is_err() {
-            return Err(ConfigError::Message(format!("Invalid language: {value}.",)));
+        if key == "output.lang" {
+            if let Some(lang) = value
+                .split(',')
+                .map(str::trim)
+                .find(|lang| *lang != "auto" && Language::from_str(lang).is_err())
+            {
+                return Err(ConfigError::Message(format!("Invalid language: {lang}.",)));
+            }
+        }
+        if key == "output.prefix_strategy" && PrefixStrategy::from_str(value).is_err() {
+            return Err(ConfigError::Message(format!(
+                "Invalid prefix strategy: {value}. Expected one of llm, heuristic, heuristic_then_llm."
+            )));
+        }
+        if key == "output.bilingual" && BilingualLayout::from_str(value).is_err() {
+            return Err(ConfigError::Message(format!(
+                "Invalid bilingual layout: {value}. Expected one of off, stacked, english_title."
+            )));
+        }
+        if key == "output.forbidden_patterns_action"
+            && ForbiddenPatternsAction::from_str(value).is_err()
+        {
+            return Err(ConfigError::Message(format!(
+                "Invalid forbidden patterns action: {value}. Expected one of sanitize, reprompt."
+            )));
+        }
+        if key == "output.on_redundant_body" && RedundantBodyAction::from_str(value).is_err() {
+            return Err(ConfigError::Message(format!(
+                "Invalid redundant body action: {value}. Expected one of keep, drop, reprompt."
+            )));
+        }
+        if key == "git.on_error" && HookErrorAction::from_str(value).is_err() {
+            return Err(ConfigError::Message(format!(
+                "Invalid hook error action: {value}. Expected one of abort, skip, empty."
+            )));
+        }
+        if key == "limits.on_exceeded" && BudgetAction::from_str(value).is_err() {
+            return Err(ConfigError::Message(format!(
+                "Invalid budget action: {value}. Expected one of error, partial."
+            )));
+        }
+        if key == "output.title_case" && TitleCase::from_str(value).is_err() {
+            return Err(ConfigError::Message(format!(
+                "Invalid title case: {value}. Expected one of as_is, lower_first, sentence."
+            )));
+        }
+        if key == "output.body_style" && BodyStyle::from_str(value).is_err() {
+            return Err(ConfigError::Message(format!(
+                "Invalid body style: {value}. Expected one of bullets, prose, what_why."
+            )));
+        }
+        if key == "output.metadata_comment" && MetadataComment::from_str(value).is_err() {
+            return Err(ConfigError::Message(format!(
+                "Invalid metadata comment: {value}. Expected one of off, git, html."
+            )));
+        }
+        if key == "output.encoding" && OutputEncoding::from_str(value).is_err() {
+            return Err(ConfigError::Message(format!(
+                "Invalid output encoding: {value}. Expected one of utf-8, utf-8-bom."
+            )));
+        }
+        if key == "output.docs_dominant_ratio"
+            && !value
+                .parse::<f64>()
+                .is_ok_and(|ratio| (0.0..=1.0).contains(&ratio))
+        {
+            return Err(ConfigError::Message(format!(
+                "Invalid docs dominant ratio: {value}. Expected a number from 0 to 1."
+            )));
+        }
+        if key == "output.summary_points_context_ratio"
+            && !value
+                .parse::<f64>()
+                .is_ok_and(|ratio| (0.0..=1.0).contains(&ratio))
+        {
+            return Err(ConfigError::Message(format!(
+                "Invalid summary points context ratio: {value}. Expected a number from 0 to 1."
+            )));
         }
         let mut settings = Self::get_config_builder()?;
         settings = settings.set_override(key, value)?;
         settings.build()?.try_deserialize()
     }
 
+    /// The settings of the config files, overridden by the environment. CLI
+    /// flags such as `--model` are applied over these by the caller.
     pub fn new() -> Result<Self, ConfigError> {
-        let settings = Self::get_config_builder()?;
-        settings.build()?.try_deserialize()
+        Self::with_env_overrides(Self::get_config_builder()?, &env_var)
     }
 
-    fn get_config_builder() -> Result<ConfigBuilder<DefaultState>, ConfigError> {
-        let mut settings = Config::builder()
+    /// The model that completes the prompts, or the name of the model provider
+    /// when it has no model setting.
+    pub(crate) fn model_name(&self) -> String {
+        let model = match self.model_provider {
+            Some(ModelProvider::OpenAI) => self.openai.as_ref().and_then(|s| s.model.clone()),
+            Some(ModelProvider::OpenRouter) => {
+                self.openrouter.as_ref().and_then(|s| s.model.clone())
+            }
+            _ => None,
+        };
+        model.unwrap_or_else(|| self.model_provider.clone().unwrap_or_default().to_string())
+    }
+
+    /// Overrides the model of the selected model provider for this run only,
+    /// e.g. from `--model`.
+    pub fn with_model_override(mut self, model: Option<&str>) -> Self {
+        let Some(model) = model else {
+            return self;
+        };
+        match self.model_provider {
+            Some(ModelProvider::OpenAI) => {
+                self.openai.get_or_insert_with(Default::default).model = Some(model.to_string());
+            }
+            Some(ModelProvider::OpenRouter) => {
+                self.openrouter.get_or_insert_with(Default::default).model =
+                    Some(model.to_string());
+            }
+            _ => warn!(
+                "A model override is not supported by the selected model provider, ignoring it"
+            ),
+        }
+        self
+    }
+
+    /// Replaces `${VAR}` references in the API keys, base URLs, proxy and
+    /// attribution of the model providers with the value of the environment
+    /// variable, so secrets can stay out of the config files. Prompts are left
+    /// as they are. Fails, naming the setting, when a variable is not set.
+    pub(crate) fn expand_env_vars(mut self) -> Result<Self, ConfigError> {
+        if let Some(openai) = self.openai.as_mut() {
+            for (key, value) in [
+                ("openai.api_base", &mut openai.api_base),
+                ("openai.api_key", &mut openai.api_key),
+                ("openai.proxy", &mut openai.proxy),
+            ] {
+                expand_env_vars_in(key, value)?;
+            }
+        }
+        if let Some(model) = self.model.as_mut() {
+            expand_env_vars_in("model.embeddings_base_url", &mut model.embeddings_base_url)?;
+        }
+        if let Some(openrouter) = self.openrouter.as_mut() {
+            for (key, value) in [
+                ("openrouter.api_base", &mut openrouter.api_base),
+                ("openrouter.api_key", &mut openrouter.api_key),
+                ("openrouter.referer", &mut openrouter.referer),
+                ("openrouter.title", &mut openrouter.title),
+            ] {
+                expand_env_vars_in(key, value)?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// The built-in defaults, without any config file or environment variable,
+    /// for embedding gptcommit as a library.
+    pub(crate) fn defaults() -> Result<Self, ConfigError> {
+        Self::get_default_config_builder()?
+            .build()?
+            .try_deserialize()
+    }
+
+    fn get_default_config_builder() -> Result<ConfigBuilder<DefaultState>, ConfigError> {
+        let settings = Config::builder()
             .set_default("allow_amend", false)?
             .set_default(
                 "file_ignore",
@@ -259,16 +1442,84 @@ impl Settings {
                     proxy: Some("".to_string()),
                 }),
             )?
+            .set_default(
+                "openrouter",
+                Some(OpenRouterSettings {
+                    api_base: Some(OPENROUTER_API_BASE.to_string()),
+                    api_key: None,
+                    model: Some(DEFAULT_OPENROUTER_MODEL.to_string()),
+                    referer: Some("".to_string()),
+                    title: Some("".to_string()),
+                }),
+            )?
+            .set_default(
+                "mock",
+                Some(MockSettings {
+                    responses: Some(vec![]),
+                }),
+            )?
+            .set_default(
+                "model",
+                Some(ModelSettings {
+                    strip_echoed_prompt: Some(false),
+                    unwrap_tags: Some(
+                        DEFAULT_UNWRAP_TAGS
+                            .iter()
+                            .map(|tag| tag.to_string())
+                            .collect(),
+                    ),
+                    pick_first_candidate: Some(true),
+                    stop_sequences: Some(vec![]),
+                    strip_preambles: Some(
+                        DEFAULT_STRIP_PREAMBLES
+                            .iter()
+                            .map(|preamble| preamble.to_string())
+                            .collect(),
+                    ),
+                    strip_code_fences: Some(true),
+                    system_prompt: Some(true),
+                    structured_prefix: Some(false),
+                    embeddings_base_url: Some("".to_string()),
+                }),
+            )?
+            .set_default(
+                "http",
+                Some(HttpSettings {
+                    proactive_throttle: Some(false),
+                }),
+            )?
+            .set_default(
+                "limits",
+                Some(LimitsSettings {
+                    max_total_tokens: Some(0),
+                    on_exceeded: Some(BudgetAction::Error.to_string()),
+                    commit_title_max_tokens: Some(0),
+                    commit_summary_max_tokens: Some(0),
+                    file_diff_max_tokens: Some(0),
+                    translation_max_tokens: Some(0),
+                }),
+            )?
             .set_default(
                 "prompt",
                 Some(PromptSettings {
+                    preamble: Some(String::new()),
                     conventional_commit_prefix: Some(
                         PROMPT_TO_CONVENTIONAL_COMMIT_PREFIX.to_string(),
                     ),
+                    conventional_commit_examples: Some(vec![]),
+                    conventional_commit_scope: Some(
+                        PROMPT_TO_CONVENTIONAL_COMMIT_SCOPE.to_string(),
+                    ),
                     file_diff: Some(PROMPT_TO_SUMMARIZE_DIFF.to_string()),
+                    file_diffs_batch: Some(PROMPT_TO_SUMMARIZE_DIFF_BATCH.to_string()),
+                    test_file_diff: Some(PROMPT_TO_SUMMARIZE_TEST_DIFF.to_string()),
+                    config_file_diff: Some(PROMPT_TO_SUMMARIZE_CONFIG_DIFF.to_string()),
+                    api_file_diff: Some(PROMPT_TO_SUMMARIZE_API_DIFF.to_string()),
+                    new_file: Some(PROMPT_TO_SUMMARIZE_NEW_FILE.to_string()),
                     commit_summary: Some(PROMPT_TO_SUMMARIZE_DIFF_SUMMARIES.to_string()),
                     commit_title: Some(PROMPT_TO_SUMMARIZE_DIFF_TITLE.to_string()),
                     translation: Some(PROMPT_TO_TRANSLATE.to_string()),
+                    translation_overrides: Some(HashMap::new()),
                 }),
             )?
             .set_default(
@@ -276,10 +1527,112 @@ impl Settings {
                 Some(OutputSettings {
                     conventional_commit: Some(true),
                     conventional_commit_prefix_format: Some("{{ prefix }}: ".to_string()),
+                    prefix_strategy: Some(PrefixStrategy::Llm.to_string()),
+                    require_scope: Some(false),
+                    allowed_commit_types: Some(
+                        DEFAULT_ALLOWED_COMMIT_TYPES
+                            .iter()
+                            .map(|commit_type| commit_type.to_string())
+                            .collect(),
+                    ),
                     lang: Some("en".to_string()),
+                    multilang_separator: Some("\n\n---\n\n".to_string()),
+                    bilingual: Some(BilingualLayout::Off.to_string()),
                     show_per_file_summary: Some(false),
+                    per_file_header: Some("".to_string()),
+                    per_file_format: Some(DEFAULT_PER_FILE_FORMAT.to_string()),
+                    per_file_summary_limit: Some(0),
+                    store_per_file_in_git_notes: Some(false),
+                    include_title: Some(true),
+                    title_strip_period: Some(true),
+                    enforce_imperative: Some(false),
+                    title_case: Some(TitleCase::AsIs.to_string()),
+                    body_style: Some(BodyStyle::Bullets.to_string()),
+                    on_redundant_body: Some(RedundantBodyAction::Keep.to_string()),
+                    encoding: Some(OutputEncoding::Utf8.to_string()),
+                    metadata_comment: Some(MetadataComment::Off.to_string()),
+                    emit_metadata_trailers: Some(false),
+                    attribution: Some("".to_string()),
+                    normalize_whitespace: Some(true),
+                    detect_wip: Some(false),
+                    offline_fallback: Some(false),
+                    respect_existing_message: Some(false),
+                    include_branch_context: Some(false),
+                    recent_commit_context: Some(0),
+                    highlight_api_changes: Some(false),
+                    respect_git_template: Some(false),
+                    retry_on_empty: Some(true),
+                    deterministic: Some(false),
+                    summarize_as_noop: Some(vec![]),
+                    // the file diff prompt already asks not to use brackets
+                    forbidden_patterns: Some(vec![r"\[".to_string(), r"\]".to_string()]),
+                    forbidden_patterns_action: Some(ForbiddenPatternsAction::Sanitize.to_string()),
+                    guard_diff_content: Some(true),
+                    injection_patterns: Some(vec![
+                        r"(?i)\b(ignore|disregard|forget) (all |any )?(the )?(previous|prior|above|earlier) (instructions|prompts?)".to_string(),
+                        r"(?i)\bnew instructions:".to_string(),
+                    ]),
+                    chunk_large_files: Some(false),
+                    chunk_token_threshold: Some(DEFAULT_CHUNK_TOKEN_THRESHOLD),
+                    test_file_patterns: Some(
+                        DEFAULT_TEST_FILE_PATTERNS
+                            .iter()
+                            .map(|s| s.to_string())
+                            .collect(),
+                    ),
+                    config_file_patterns: Some(
+                        DEFAULT_CONFIG_FILE_PATTERNS
+                            .iter()
+                            .map(|s| s.to_string())
+                            .collect(),
+                    ),
+                    ci_path_patterns: Some(
+                        DEFAULT_CI_PATH_PATTERNS
+                            .iter()
+                            .map(|s| s.to_string())
+                            .collect(),
+                    ),
+                    docs_dominant_ratio: Some(DEFAULT_DOCS_DOMINANT_RATIO),
+                    summary_points_context_ratio: Some(DEFAULT_SUMMARY_POINTS_CONTEXT_RATIO),
+                    new_file_token_threshold: Some(DEFAULT_NEW_FILE_TOKEN_THRESHOLD),
+                    max_files: Some(DEFAULT_MAX_FILES),
+                    max_files_before_overview: Some(DEFAULT_MAX_FILES_BEFORE_OVERVIEW),
+                    max_summary_bullets: Some(DEFAULT_MAX_SUMMARY_BULLETS),
+                    max_file_bullets: Some(DEFAULT_MAX_FILE_BULLETS),
+                    ignore_whitespace_only: Some(true),
+                    diff_context_lines: Some(-1),
+                }),
+            )?
+            .set_default(
+                "git",
+                Some(GitSettings {
+                    branch_ticket_regex: Some(DEFAULT_BRANCH_TICKET_REGEX.to_string()),
+                    branch_ticket_footer: Some("Refs: {{ tickets }}".to_string()),
+                    co_authors: Some(vec![]),
+                    detached_head_ticket_env: Some("".to_string()),
+                    skip_commit_sources: Some(
+                        DEFAULT_SKIP_COMMIT_SOURCES
+                            .iter()
+                            .map(|source| source.to_string())
+                            .collect(),
+                    ),
+                    on_error: Some(HookErrorAction::Abort.to_string()),
+                }),
+            )?
+            .set_default(
+                "postprocess",
+                Some(PostprocessSettings {
+                    commit_title: Some(vec![]),
+                    commit_summary: Some(vec![]),
+                    file_diff: Some(vec![]),
+                    translation: Some(vec![]),
                 }),
             )?;
+        Ok(settings)
+    }
+
+    fn get_config_builder() -> Result<ConfigBuilder<DefaultState>, ConfigError> {
+        let mut settings = Self::get_default_config_builder()?;
 
         if let Some(home_dir) = dirs::home_dir() {
             debug!("Using home dir at {}", home_dir.display());
@@ -317,24 +1670,47 @@ impl Settings {
         );
         settings = settings.add_source(app_env);
 
-        // add custom override
-        if let Ok(openai_api_base) = std::env::var("OPENAI_API_BASE") {
-            if !openai_api_base.is_empty() {
-                debug!("Applying OPENAI_API_BASE envvar: {}", openai_api_base);
-                settings = settings.set_override("openai.api_base", Some(openai_api_base))?;
-            }
-        }
-        if let Ok(openai_api_key) = std::env::var("OPENAI_API_KEY") {
-            if !openai_api_key.is_empty() {
-                debug!("Applying OPENAI_API_KEY envvar: {}", openai_api_key);
-                settings = settings.set_override("openai.api_key", Some(openai_api_key))?;
+        Ok(settings)
+    }
+
+    /// Builds the settings with the variables of [`ENV_OVERRIDES`] and
+    /// `GPTCOMMIT_MODEL` applied over the config files, for this run only:
+    /// `config set` and `config delete` build from the files alone, so they
+    /// never save these values. Empty variables are ignored.
+    fn with_env_overrides(
+        mut settings: ConfigBuilder<DefaultState>,
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        for (name, key) in ENV_OVERRIDES {
+            if let Some(value) = env(name).filter(|v| !v.is_empty()) {
+                debug!("Applying {} envvar to {}", name, key);
+                settings = settings.set_override(*key, Some(value))?;
             }
         }
-
-        Ok(settings)
+        let settings: Self = settings.build()?.try_deserialize()?;
+        // the model setting lives under the provider, so it's applied once
+        // the provider is resolved
+        let model = env(MODEL_ENV_VAR).filter(|v| !v.is_empty());
+        Ok(settings.with_model_override(model.as_deref()))
     }
 }
 
+/// Environment variables that override a setting of the config files, and are
+/// in turn overridden by CLI flags.
+static ENV_OVERRIDES: &[(&str, &str)] = &[
+    ("GPTCOMMIT_MODEL_PROVIDER", "model_provider"),
+    ("OPENAI_API_BASE", "openai.api_base"),
+    ("OPENAI_API_KEY", "openai.api_key"),
+    ("OPENROUTER_API_KEY", "openrouter.api_key"),
+];
+
+/// Overrides the model of the selected model provider, like `--model`.
+const MODEL_ENV_VAR: &str = "GPTCOMMIT_MODEL";
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
 pub fn get_local_config_path() -> Option<PathBuf> {
     if let Ok(config_dir) = get_hooks_path() {
         if !config_dir.is_dir() {
@@ -372,3 +1748,185 @@ pub fn get_user_config_path() -> Option<PathBuf> {
     None
 }
 const APP_NAME: &str = "gptcommit";
+
+lazy_static! {
+    static ref ENV_VAR_REGEX: Regex = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+}
+
+/// Expands the `${VAR}` references in the value of the setting `key`.
+fn expand_env_vars_in(key: &str, value: &mut Option<String>) -> Result<(), ConfigError> {
+    let Some(text) = value.as_deref() else {
+        return Ok(());
+    };
+    let mut expanded = String::with_capacity(text.len());
+    let mut last = 0;
+    for captures in ENV_VAR_REGEX.captures_iter(text) {
+        let reference = captures.get(0).unwrap();
+        let name = &captures[1];
+        let var = std::env::var(name).map_err(|_| {
+            ConfigError::Message(format!(
+                "{key} references the environment variable {name}, which is not set."
+            ))
+        })?;
+        expanded.push_str(&text[last..reference.start()]);
+        expanded.push_str(&var);
+        last = reference.end();
+    }
+    expanded.push_str(&text[last..]);
+    *value = Some(expanded);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    #[test]
+    fn test_expand_env_vars() {
+        std::env::set_var("GPTCOMMIT_TEST_API_KEY", "sk-from-env");
+        std::env::set_var("GPTCOMMIT_TEST_HOST", "llm.internal");
+        let mut settings = Settings::defaults().unwrap();
+        let openai = settings.openai.as_mut().unwrap();
+        openai.api_key = Some("${GPTCOMMIT_TEST_API_KEY}".to_string());
+        openai.api_base = Some("https://${GPTCOMMIT_TEST_HOST}/v1".to_string());
+        openai.proxy = Some("$HOME costs $5".to_string());
+        let prompt = "Costs ${GPTCOMMIT_TEST_API_KEY} and $PATH{{ summary_points }}";
+        settings.prompt.as_mut().unwrap().commit_title = Some(prompt.to_string());
+
+        let settings = settings.expand_env_vars().unwrap();
+        let openai = settings.openai.as_ref().unwrap();
+        assert_eq!(openai.api_key.as_deref(), Some("sk-from-env"));
+        assert_eq!(openai.api_base.as_deref(), Some("https://llm.internal/v1"));
+        assert_eq!(openai.proxy.as_deref(), Some("$HOME costs $5"));
+        assert_eq!(
+            settings.prompt.unwrap().commit_title.as_deref(),
+            Some(prompt)
+        );
+    }
+
+    #[test]
+    fn test_env_overrides_config_and_cli_overrides_env() {
+        let config = r#"
+            model_provider = "openai"
+
+            [openai]
+            model = "gpt-from-config"
+            api_key = "sk-from-config"
+
+            [openrouter]
+            model = "openrouter-from-config"
+        "#;
+        let env = |name: &str| {
+            match name {
+                "GPTCOMMIT_MODEL_PROVIDER" => Some("openrouter"),
+                "GPTCOMMIT_MODEL" => Some("model-from-env"),
+                "OPENROUTER_API_KEY" => Some("sk-or-from-env"),
+                "OPENAI_API_KEY" => Some(""),
+                _ => None,
+            }
+            .map(str::to_string)
+        };
+        let builder = Settings::get_default_config_builder()
+            .unwrap()
+            .add_source(File::from_str(config, FileFormat::Toml));
+        let settings = Settings::with_env_overrides(builder, &env).unwrap();
+
+        assert!(matches!(
+            settings.model_provider,
+            Some(ModelProvider::OpenRouter)
+        ));
+        assert_eq!(settings.model_name(), "model-from-env");
+        let openrouter = settings.openrouter.as_ref().unwrap();
+        assert_eq!(openrouter.api_key.as_deref(), Some("sk-or-from-env"));
+        // empty variables leave the config as it is
+        let openai = settings.openai.as_ref().unwrap();
+        assert_eq!(openai.api_key.as_deref(), Some("sk-from-config"));
+        assert_eq!(openai.model.as_deref(), Some("gpt-from-config"));
+
+        let settings = settings.with_model_override(Some("model-from-cli"));
+        assert_eq!(settings.model_name(), "model-from-cli");
+    }
+
+    #[test]
+    fn test_config_set_does_not_save_env_overrides() {
+        let env_api_key = env_var("OPENAI_API_KEY").filter(|v| !v.is_empty());
+        let settings = Settings::from_set_override("openai.model", "gpt-from-set").unwrap();
+        let openai = settings.openai.unwrap();
+        assert_eq!(openai.model.as_deref(), Some("gpt-from-set"));
+        if env_api_key.is_some() {
+            assert_ne!(openai.api_key, env_api_key);
+        }
+
+        let settings = Settings::from_clear("openai.model").unwrap();
+        if env_api_key.is_some() {
+            assert_ne!(settings.openai.unwrap().api_key, env_api_key);
+        }
+    }
+
+    #[test]
+    fn test_expand_unset_env_var_fails() {
+        std::env::remove_var("GPTCOMMIT_TEST_UNSET_KEY");
+        let mut settings = Settings::defaults().unwrap();
+        settings.openrouter.as_mut().unwrap().api_key =
+            Some("${GPTCOMMIT_TEST_UNSET_KEY}".to_string());
+
+        let err = settings.expand_env_vars().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "openrouter.api_key references the environment variable GPTCOMMIT_TEST_UNSET_KEY, which is not set."
+        );
+    }
+
+    #[test]
+    fn test_lang_accepts_a_list() {
+        let output: OutputSettings = toml::from_str(r#"lang = ["en", "ja"]"#).unwrap();
+        assert_eq!(output.lang.as_deref(), Some("en,ja"));
+
+        let output: OutputSettings = toml::from_str(r#"lang = "zh-tw""#).unwrap();
+        assert_eq!(output.lang.as_deref(), Some("zh-tw"));
+
+        let output: OutputSettings = toml::from_str("").unwrap();
+        assert_eq!(output.lang, None);
+    }
+
+    #[test]
+    fn test_language_from_str() {
+        assert_eq!(Language::from_str("ja"), Ok(Language::Ja));
+        assert_eq!(Language::from_str("zh-CN"), Ok(Language::ZhCn));
+        assert_eq!(Language::from_str("en-US"), Ok(Language::En));
+        assert_eq!(Language::Ja.to_string(), "Japanese");
+
+        let lang = Language::from_str("pt-BR").unwrap();
+        assert_eq!(lang, Language::Other("pt-BR".to_string()));
+        assert_eq!(lang.to_string(), "Brazilian Portuguese");
+        assert_eq!(Language::from_str("uk").unwrap().to_string(), "Ukrainian");
+        assert_eq!(Language::from_str("de-AT").unwrap().to_string(), "German");
+        assert_eq!(Language::from_str("tlh").unwrap().to_string(), "tlh");
+
+        assert!(Language::from_str("").is_err());
+        assert!(Language::from_str("english please").is_err());
+        assert!(Language::from_str("x").is_err());
+        assert!(Language::from_str("pt_BR").is_err());
+    }
+
+    #[test]
+    fn test_title_case() {
+        assert_eq!(TitleCase::AsIs.apply("Add a widget"), "Add a widget");
+        assert_eq!(TitleCase::LowerFirst.apply("Add a widget"), "add a widget");
+        assert_eq!(
+            TitleCase::LowerFirst.apply("README updates"),
+            "README updates"
+        );
+        assert_eq!(
+            TitleCase::LowerFirst.apply("OpenAI client"),
+            "OpenAI client"
+        );
+        assert_eq!(TitleCase::Sentence.apply("add a widget"), "Add a widget");
+        assert_eq!(
+            TitleCase::Sentence.apply("élargir le widget"),
+            "Élargir le widget"
+        );
+        assert_eq!(TitleCase::Sentence.apply(""), "");
+    }
+}
@@ -72,20 +72,107 @@ the-force = { value = "surrounds-you" }
         vec![
             "allow_amend",
             "file_ignore",
+            "git.branch_ticket_footer",
+            "git.branch_ticket_regex",
+            "git.co_authors",
+            "git.detached_head_ticket_env",
+            "git.on_error",
+            "git.skip_commit_sources",
+            "http.proactive_throttle",
+            "limits.commit_summary_max_tokens",
+            "limits.commit_title_max_tokens",
+            "limits.file_diff_max_tokens",
+            "limits.max_total_tokens",
+            "limits.on_exceeded",
+            "limits.translation_max_tokens",
+            "mock.responses",
+            "model.embeddings_base_url",
+            "model.pick_first_candidate",
+            "model.stop_sequences",
+            "model.strip_code_fences",
+            "model.strip_echoed_prompt",
+            "model.strip_preambles",
+            "model.structured_prefix",
+            "model.system_prompt",
+            "model.unwrap_tags",
             "model_provider",
             "openai.api_base",
             "openai.api_key",
             "openai.model",
             "openai.proxy",
             "openai.retries",
+            "openrouter.api_base",
+            "openrouter.model",
+            "openrouter.referer",
+            "openrouter.title",
+            "output.allowed_commit_types",
+            "output.attribution",
+            "output.bilingual",
+            "output.body_style",
+            "output.chunk_large_files",
+            "output.chunk_token_threshold",
+            "output.ci_path_patterns",
+            "output.config_file_patterns",
             "output.conventional_commit",
             "output.conventional_commit_prefix_format",
+            "output.detect_wip",
+            "output.deterministic",
+            "output.diff_context_lines",
+            "output.docs_dominant_ratio",
+            "output.emit_metadata_trailers",
+            "output.encoding",
+            "output.enforce_imperative",
+            "output.forbidden_patterns",
+            "output.forbidden_patterns_action",
+            "output.guard_diff_content",
+            "output.highlight_api_changes",
+            "output.ignore_whitespace_only",
+            "output.include_branch_context",
+            "output.include_title",
+            "output.injection_patterns",
             "output.lang",
+            "output.max_file_bullets",
+            "output.max_files",
+            "output.max_files_before_overview",
+            "output.max_summary_bullets",
+            "output.metadata_comment",
+            "output.multilang_separator",
+            "output.new_file_token_threshold",
+            "output.normalize_whitespace",
+            "output.offline_fallback",
+            "output.on_redundant_body",
+            "output.per_file_format",
+            "output.per_file_header",
+            "output.per_file_summary_limit",
+            "output.prefix_strategy",
+            "output.recent_commit_context",
+            "output.require_scope",
+            "output.respect_existing_message",
+            "output.respect_git_template",
+            "output.retry_on_empty",
             "output.show_per_file_summary",
+            "output.store_per_file_in_git_notes",
+            "output.summarize_as_noop",
+            "output.summary_points_context_ratio",
+            "output.test_file_patterns",
+            "output.title_case",
+            "output.title_strip_period",
+            "postprocess.commit_summary",
+            "postprocess.commit_title",
+            "postprocess.file_diff",
+            "postprocess.translation",
+            "prompt.api_file_diff",
             "prompt.commit_summary",
             "prompt.commit_title",
+            "prompt.config_file_diff",
+            "prompt.conventional_commit_examples",
             "prompt.conventional_commit_prefix",
+            "prompt.conventional_commit_scope",
             "prompt.file_diff",
+            "prompt.file_diffs_batch",
+            "prompt.new_file",
+            "prompt.preamble",
+            "prompt.test_file_diff",
             "prompt.translation",
         ]
     }